@@ -21,6 +21,13 @@ struct PacmanCleanHook {
     prefix: String,
 }
 
+#[derive(Debug, Template)]
+#[template(path = "dracut-efi-mark-good.service", escape = "none")]
+#[allow(dead_code)]
+struct MarkGoodService {
+    prefix: String,
+}
+
 fn write_to_file(path: &Path, content: &dyn ToString) -> Result<(), Error> {
     let parent_dir = path.parent().unwrap();
     if !parent_dir.exists() {
@@ -63,4 +70,12 @@ fn main() {
             prefix: prefix.clone(),
         } as &dyn ToString,
     );
+    let _ = write_to_file(
+        &binary_dir
+            .join("systemd")
+            .join("dracut-efi-mark-good.service"),
+        &MarkGoodService {
+            prefix: prefix.clone(),
+        } as &dyn ToString,
+    );
 }
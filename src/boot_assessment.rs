@@ -0,0 +1,182 @@
+//! Boot assessment of newly built images
+//!
+//! A new image is not moved to the front of the boot order right away. It is booted once via
+//! `BootNext` while the previous first entry stays in place, so the firmware falls back to it if
+//! the new kernel does not come up. Once the system booted successfully `mark-good`, run by a
+//! systemd unit after `boot-complete.target`, promotes the entry to the front of the boot order.
+//!
+//! The pending trial is remembered in a state file between the build and the next boot.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    boot_order::{self, set_boot_next},
+    error::ManagerError,
+    find_efi_partition,
+    firmware::read_u16,
+    kernel_version::KernelVersion,
+    BuildJob, EfiStubBuildConfig,
+};
+
+#[cfg(debug_assertions)]
+const TRIAL_STATE_FILE: &str = "trial.json";
+
+#[cfg(not(debug_assertions))]
+const TRIAL_STATE_FILE: &str = "/var/lib/dracut-efi-manager/trial.json";
+
+/// a boot entry that is booted once and waits for confirmation
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BootTrial {
+    pub id: u16,
+    pub image: PathBuf,
+    pub version: String,
+}
+
+/// the trial waiting for confirmation, if any
+pub fn pending_trial() -> Option<BootTrial> {
+    fs::read_to_string(TRIAL_STATE_FILE)
+        .ok()
+        .and_then(|state| serde_json::from_str(&state).ok())
+}
+
+fn save_trial(trial: &BootTrial) {
+    let state_file = Path::new(TRIAL_STATE_FILE);
+    if let Some(parent) = state_file.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    if let Err(err) = fs::write(state_file, serde_json::to_string(trial).unwrap()) {
+        eprintln!("Could not save boot trial to {TRIAL_STATE_FILE}: {err}");
+    }
+}
+
+fn clear_trial() {
+    let _ = fs::remove_file(TRIAL_STATE_FILE);
+}
+
+/// move `id` behind the first entry, so the firmware falls back to the current first entry
+pub fn demote(order: &[u16], id: u16) -> Vec<u16> {
+    let mut new_order: Vec<u16> = order.iter().copied().filter(|entry| *entry != id).collect();
+    new_order.insert(new_order.len().min(1), id);
+    new_order
+}
+
+/// boot the newest newly built primary image once instead of promoting it right away
+///
+/// Only images that did not exist before the build are trialled, destinations therefore need
+/// to contain `{version}`. Rebuilding an image in place cannot fall back to the previous kernel.
+pub fn start_trial(
+    settings: &EfiStubBuildConfig,
+    new_images: &[BuildJob],
+    dry_run: bool,
+) -> Result<(), ManagerError> {
+    if !settings.boot_assessment {
+        return Ok(());
+    }
+    let Some(job) = new_images
+        .iter()
+        .filter(|job| !job.retained)
+        .max_by_key(|job| KernelVersion::parse(&job.version))
+    else {
+        return Ok(());
+    };
+    let Some((efi_part, mount_dir)) = find_efi_partition(Path::new(&settings.efi_dir)) else {
+        return Ok(());
+    };
+    let image = job
+        .destination
+        .strip_prefix(&mount_dir)
+        .unwrap()
+        .to_path_buf();
+    let Some((id, _)) = efi_part
+        .boot_entries_by_id()?
+        .into_iter()
+        .find(|(_, (file, _))| *file == image)
+    else {
+        if dry_run {
+            println!("Would boot {} once to test it", image.display());
+        } else {
+            eprintln!(
+                "{} has no boot entry, it cannot be booted for testing.",
+                image.display()
+            );
+        }
+        return Ok(());
+    };
+    let boot_order = efivar::system().get_boot_order()?;
+    if boot_order.first() == Some(&id) {
+        boot_order::set_boot_order(&boot_order, demote(&boot_order, id), dry_run)?;
+    }
+    if dry_run {
+        println!(
+            "Would boot entry {id:04X} once to test kernel {}",
+            job.version
+        );
+        return Ok(());
+    }
+    set_boot_next(id)?;
+    save_trial(&BootTrial {
+        id,
+        image,
+        version: job.version.clone(),
+    });
+    println!(
+        "Entry {id:04X} with kernel {} is booted once on the next boot, run `mark-good` after a successful boot to keep it.",
+        job.version
+    );
+    Ok(())
+}
+
+/// promote the trialled entry to the front of the boot order if it is the current boot
+pub fn mark_good(dry_run: bool) -> Result<(), ManagerError> {
+    let Some(trial) = pending_trial() else {
+        println!("No boot trial pending.");
+        return Ok(());
+    };
+    let current = read_u16("BootCurrent");
+    if current != Some(trial.id) {
+        if read_u16("BootNext") == Some(trial.id) {
+            println!(
+                "Entry {:04X} with kernel {} has not been booted yet.",
+                trial.id, trial.version
+            );
+            return Ok(());
+        }
+        println!(
+            "Entry {:04X} with kernel {} did not boot successfully, keeping the boot order.",
+            trial.id, trial.version
+        );
+        if !dry_run {
+            clear_trial();
+        }
+        return Ok(());
+    }
+    let boot_order = efivar::system().get_boot_order()?;
+    let mut new_order = vec![trial.id];
+    new_order.extend(boot_order.iter().copied().filter(|id| *id != trial.id));
+    boot_order::set_boot_order(&boot_order, new_order, dry_run)?;
+    if !dry_run {
+        clear_trial();
+        println!(
+            "Kernel {} booted successfully, entry {:04X} is now booted first.",
+            trial.version, trial.id
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod boot_trial_tests {
+    use super::demote;
+
+    #[test]
+    fn demote_test() {
+        assert_eq!(demote(&[5, 1, 2], 5), vec![1, 5, 2]);
+        assert_eq!(demote(&[1, 2], 5), vec![1, 5, 2]);
+        assert_eq!(demote(&[1, 5, 2], 5), vec![1, 5, 2]);
+        assert_eq!(demote(&[], 5), vec![5]);
+    }
+}
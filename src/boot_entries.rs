@@ -0,0 +1,648 @@
+//! Declarative firmware boot entries
+//!
+//! The settings may list the images that should have a firmware boot entry together with its
+//! description and position in the boot order. `bootentries --apply` creates missing entries,
+//! updates entries whose description differs or that were deactivated and moves them to their
+//! configured positions.
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use efivar::{
+    boot::{BootEntry, BootEntryAttributes},
+    efi::Variable,
+};
+use gpt::partition_types;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    add_boot_entry, boot_entry_file,
+    boot_order::{self, format_boot_order},
+    delete_boot_entry, destination,
+    error::ManagerError,
+    find_efi_partition, get_efi_partitions, get_free_boot_id, get_gpt_partitions, get_machine_id,
+    get_ranked_images, read_boot_variable, used_boot_ids, BootCategory, BuildJob,
+    EfiStubBuildConfig, ImageLayout,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeclaredBootEntry {
+    /// path of the image relative to `efi_dir`
+    pub image: String,
+    pub description: String,
+    /// index in the boot order, the entry keeps its place or is appended if unset
+    pub position: Option<usize>,
+}
+
+/// a change to the firmware boot entries needed to match the configuration
+#[derive(Debug)]
+enum EntryChange {
+    Create {
+        image: PathBuf,
+        entry: BootEntry,
+        position: Option<usize>,
+    },
+    Update {
+        id: u16,
+        entry: BootEntry,
+        position: Option<usize>,
+    },
+    Keep {
+        id: u16,
+        position: Option<usize>,
+    },
+}
+
+/// move the ids to their positions in `order`, lower positions are placed first
+///
+/// Positions beyond the end of the boot order append the id.
+pub fn apply_positions(order: &[u16], positioned: &[(u16, usize)]) -> Vec<u16> {
+    let mut positioned = positioned.to_vec();
+    positioned.sort_by_key(|(_, position)| *position);
+    let mut new_order: Vec<u16> = order
+        .iter()
+        .copied()
+        .filter(|id| {
+            !positioned
+                .iter()
+                .any(|(positioned_id, _)| positioned_id == id)
+        })
+        .collect();
+    for (id, position) in positioned {
+        new_order.insert(position.min(new_order.len()), id);
+    }
+    new_order
+}
+
+/// create, update and reorder firmware boot entries to match `boot_entries` of the settings
+pub fn apply_boot_entries(
+    settings: &EfiStubBuildConfig,
+    yes: bool,
+    dry_run: bool,
+) -> Result<(), ManagerError> {
+    let efi_dir = Path::new(&settings.efi_dir);
+    let Some((efi_part, mount_dir)) = find_efi_partition(efi_dir) else {
+        println!(
+            "{} is not on a mounted efi partition. No boot entries to configure.",
+            settings.efi_dir
+        );
+        return Ok(());
+    };
+    let existing_entries = efi_part.boot_entries_by_id()?;
+
+    let mut changes = Vec::new();
+    for declared in &settings.boot_entries {
+        let image = efi_dir.join(&declared.image);
+        if !image.is_file() {
+            eprintln!(
+                "Efi binary {} does not exist, skipping its boot entry.",
+                image.display()
+            );
+            continue;
+        }
+        let Ok(image) = image.strip_prefix(&mount_dir).map(Path::to_path_buf) else {
+            eprintln!(
+                "Efi binary {} is not on the efi partition mounted at {}, skipping its boot entry.",
+                image.display(),
+                mount_dir.display()
+            );
+            continue;
+        };
+        let existing = existing_entries
+            .iter()
+            .find(|(_, (path, _))| *path == image);
+        changes.push(match existing {
+            Some((id, (_, entry)))
+                if entry.description == declared.description
+                    && entry
+                        .attributes
+                        .contains(BootEntryAttributes::LOAD_OPTION_ACTIVE) =>
+            {
+                EntryChange::Keep {
+                    id: *id,
+                    position: declared.position,
+                }
+            }
+            Some((id, (_, entry))) => {
+                let mut entry = entry.clone();
+                entry.description = declared.description.clone();
+                entry.attributes |= BootEntryAttributes::LOAD_OPTION_ACTIVE;
+                EntryChange::Update {
+                    id: *id,
+                    entry,
+                    position: declared.position,
+                }
+            }
+            None => EntryChange::Create {
+                entry: efi_part.gen_boot_entry(&image, declared.description.clone()),
+                image,
+                position: declared.position,
+            },
+        });
+    }
+
+    // ids of new entries are only known once they are created, reserve them in order
+    let boot_order = efivar::system().get_boot_order()?;
+    let mut used_ids = used_boot_ids()?;
+    let mut new_order = boot_order.clone();
+    let mut positioned = Vec::new();
+    let mut pending_writes = Vec::new();
+    for change in changes {
+        match change {
+            EntryChange::Create {
+                image,
+                entry,
+                position,
+            } => {
+                let id = get_free_boot_id(&used_ids);
+                used_ids.push(id);
+                println!(
+                    "Create boot entry {id:04X} `{}` for {}",
+                    entry.description,
+                    image.display()
+                );
+                new_order.push(id);
+                positioned.extend(position.map(|position| (id, position)));
+                pending_writes.push((id, entry));
+            }
+            EntryChange::Update {
+                id,
+                entry,
+                position,
+            } => {
+                println!("Update boot entry {id:04X} to `{}`", entry.description);
+                positioned.extend(position.map(|position| (id, position)));
+                pending_writes.push((id, entry));
+            }
+            EntryChange::Keep { id, position } => {
+                positioned.extend(position.map(|position| (id, position)));
+            }
+        }
+    }
+    let new_order = apply_positions(&new_order, &positioned);
+    let reorder = new_order != boot_order;
+    if reorder {
+        println!(
+            "Change boot order from {} to {}",
+            format_boot_order(&boot_order),
+            format_boot_order(&new_order)
+        );
+    }
+
+    if pending_writes.is_empty() && !reorder {
+        println!("Boot entries already match the configuration.");
+        return Ok(());
+    }
+    if dry_run {
+        println!("Dry run, no boot entries were changed.");
+        return Ok(());
+    }
+    if !yes
+        && !dialoguer::Confirm::new()
+            .with_prompt("Apply these changes to the firmware boot entries?")
+            .interact()?
+    {
+        return Ok(());
+    }
+    for (id, entry) in pending_writes {
+        efivar::system().add_boot_entry(id, entry)?;
+    }
+    if reorder {
+        efivar::system().set_boot_order(new_order)?;
+    }
+    Ok(())
+}
+
+/// create boot entries for built images that have none yet, named by `boot_entry_description`
+///
+/// Images in the bls layout are found by the boot loader and renamed while counting boot
+/// attempts, so they are never registered.
+pub fn register_built_images(
+    settings: &EfiStubBuildConfig,
+    jobs: &[BuildJob],
+    dry_run: bool,
+) -> Result<(), ManagerError> {
+    let Some(description_template) = &settings.boot_entry_description else {
+        return Ok(());
+    };
+    if settings.layout == ImageLayout::Bls || jobs.is_empty() {
+        return Ok(());
+    }
+    let Some((efi_part, mount_dir)) = find_efi_partition(Path::new(&settings.efi_dir)) else {
+        eprintln!(
+            "{} is not on a mounted efi partition, no boot entries were created.",
+            settings.efi_dir
+        );
+        return Ok(());
+    };
+    let existing_entries = efi_part.existing_boot_entries();
+    let machine_id = get_machine_id();
+    let ranked_images = get_ranked_images(settings, &mount_dir);
+    for job in jobs {
+        let Ok(image) = job.destination.strip_prefix(&mount_dir) else {
+            continue;
+        };
+        if existing_entries.contains_key(image) {
+            continue;
+        }
+        let values = settings
+            .build_mappings
+            .get(&job.kernel)
+            .unwrap()
+            .template_values(&job.kernel, &machine_id);
+        let description = destination::render(description_template, &values, &job.version);
+        if dry_run {
+            println!(
+                "Would create boot entry `{description}` for {}",
+                image.display()
+            );
+            continue;
+        }
+        println!(
+            "Creating boot entry `{description}` for {}",
+            image.display()
+        );
+        let position = match &settings.boot_order {
+            Some(policy) => Some(boot_order::insert_position(policy, &ranked_images, image)?),
+            None => None,
+        };
+        add_boot_entry(efi_part.gen_boot_entry(image, description), position)?;
+    }
+    Ok(())
+}
+
+/// a firmware boot entry pointing to a partition or file that does not exist anymore
+struct StaleEntry {
+    id: u16,
+    description: String,
+    reason: String,
+}
+
+/// find boot entries whose partition is gone or whose image is missing on its partition
+///
+/// Entries without a hard drive device path, like network boot or firmware applications, are
+/// never considered stale. Partitions that cannot be mounted are skipped.
+fn find_stale_entries(
+    partitions: &[crate::EfiPartionInfo],
+) -> Result<Vec<StaleEntry>, efivar::Error> {
+    let mut stale_entries = Vec::new();
+    let mut entries_by_partition: BTreeMap<usize, Vec<(u16, String, PathBuf)>> = BTreeMap::new();
+    for entry in efivar::system().get_boot_entries()? {
+        let Ok(boot_var) = entry.0 else {
+            continue;
+        };
+        let Some(boot_path) = &boot_var.entry.file_path_list else {
+            continue;
+        };
+        let file = boot_entry_file(boot_path);
+        match partitions
+            .iter()
+            .position(|part| part.info.part_guid == boot_path.hard_drive.partition_sig)
+        {
+            None => stale_entries.push(StaleEntry {
+                id: boot_var.id,
+                description: boot_var.entry.description,
+                reason: format!(
+                    "partition {} does not exist",
+                    boot_path.hard_drive.partition_sig
+                ),
+            }),
+            Some(_) if file.as_os_str().is_empty() => {}
+            // only efi partitions are mounted to look for the image
+            Some(part) if partitions[part].info.part_type_guid != partition_types::EFI => {}
+            Some(part) => entries_by_partition.entry(part).or_default().push((
+                boot_var.id,
+                boot_var.entry.description,
+                file,
+            )),
+        }
+    }
+    for (part, entries) in entries_by_partition {
+        let missing = partitions[part].with_mount_dir(|mount_dir| {
+            entries
+                .into_iter()
+                .filter(|(_, _, file)| !mount_dir.join(file).is_file())
+                .collect::<Vec<_>>()
+        });
+        for (id, description, file) in missing.into_iter().flatten() {
+            stale_entries.push(StaleEntry {
+                id,
+                description,
+                reason: format!("{} does not exist", file.display()),
+            });
+        }
+    }
+    stale_entries.sort_by_key(|stale| stale.id);
+    Ok(stale_entries)
+}
+
+/// delete boot entries pointing to partitions or files that do not exist anymore
+pub fn prune_boot_entries(yes: bool, dry_run: bool) -> Result<(), ManagerError> {
+    let partitions = get_gpt_partitions();
+    // without access to the disks every entry would look stale
+    if partitions.is_empty() {
+        println!("No gpt partitions found. Not pruning any boot entries.");
+        return Ok(());
+    }
+    let stale_entries = find_stale_entries(&partitions)?;
+    if stale_entries.is_empty() {
+        println!("No stale boot entries found.");
+        return Ok(());
+    }
+    for stale in &stale_entries {
+        println!(
+            "Stale boot entry {:04X} `{}`: {}",
+            stale.id, stale.description, stale.reason
+        );
+    }
+    if dry_run {
+        println!("Dry run, no boot entries were removed.");
+        return Ok(());
+    }
+    if !yes
+        && !dialoguer::Confirm::new()
+            .with_prompt("Remove these boot entries?")
+            .interact()?
+    {
+        return Ok(());
+    }
+    for stale in stale_entries {
+        delete_boot_entry(stale.id)?;
+    }
+    Ok(())
+}
+
+/// delete the boot entries of efi binaries that were removed from `efi_dir`
+pub fn remove_entries_for_images(
+    settings: &EfiStubBuildConfig,
+    removed_images: &[PathBuf],
+    dry_run: bool,
+) -> Result<(), ManagerError> {
+    if removed_images.is_empty() {
+        return Ok(());
+    }
+    let Some((efi_part, mount_dir)) = find_efi_partition(Path::new(&settings.efi_dir)) else {
+        return Ok(());
+    };
+    for (id, (file, entry)) in efi_part.boot_entries_by_id()? {
+        let removed = removed_images.iter().any(|image| {
+            image
+                .strip_prefix(&mount_dir)
+                .is_ok_and(|image| image == file)
+        });
+        if removed && dry_run {
+            println!("Would remove boot entry {id:04X} `{}`", entry.description);
+        } else if removed {
+            println!("Removing boot entry {id:04X} `{}`", entry.description);
+            delete_boot_entry(id)?;
+        }
+    }
+    Ok(())
+}
+
+/// rewrite boot entries pointing to an efi partition whose number, start or size changed
+///
+/// Entries are matched to partitions by the partition GUID, which survives moving and resizing.
+/// Some firmware ignores entries whose device path does not match the partition table.
+pub fn repair_boot_entries(yes: bool, dry_run: bool) -> Result<(), ManagerError> {
+    let efi_partitions = get_efi_partitions();
+    let mut repairs = Vec::new();
+    for entry in efivar::system().get_boot_entries()? {
+        let Ok(mut boot_var) = entry.0 else {
+            continue;
+        };
+        let Some(boot_path) = &mut boot_var.entry.file_path_list else {
+            continue;
+        };
+        let Some(efi_part) = efi_partitions
+            .iter()
+            .find(|part| part.info.part_guid == boot_path.hard_drive.partition_sig)
+        else {
+            continue;
+        };
+        let current = efi_part.hard_drive();
+        let stored = &boot_path.hard_drive;
+        if (
+            stored.partition_number,
+            stored.partition_start,
+            stored.partition_size,
+        ) == (
+            current.partition_number,
+            current.partition_start,
+            current.partition_size,
+        ) {
+            continue;
+        }
+        println!(
+            "Boot entry {:04X} `{}` points to partition {} at start {} with size {}, the partition is now {} at start {} with size {}",
+            boot_var.id,
+            boot_var.entry.description,
+            stored.partition_number,
+            stored.partition_start,
+            stored.partition_size,
+            current.partition_number,
+            current.partition_start,
+            current.partition_size
+        );
+        boot_path.hard_drive = current;
+        repairs.push((boot_var.id, boot_var.entry));
+    }
+    if repairs.is_empty() {
+        println!("All boot entries match the partition table.");
+        return Ok(());
+    }
+    if dry_run {
+        println!("Dry run, no boot entries were rewritten.");
+        return Ok(());
+    }
+    if !yes
+        && !dialoguer::Confirm::new()
+            .with_prompt("Rewrite these boot entries?")
+            .interact()?
+    {
+        return Ok(());
+    }
+    for (id, entry) in repairs {
+        efivar::system().add_boot_entry(id, entry)?;
+    }
+    Ok(())
+}
+
+/// changes to a boot entry, unset fields are left as they are
+pub struct EntryEdit {
+    pub description: Option<String>,
+    pub active: Option<bool>,
+    pub hidden: Option<bool>,
+    pub category: Option<BootCategory>,
+}
+
+impl EntryEdit {
+    /// apply the changes to a description and attributes, returns false if nothing changed
+    fn apply(&self, description: &mut String, attributes: &mut BootEntryAttributes) -> bool {
+        let previous = (description.clone(), *attributes);
+        if let Some(new_description) = &self.description {
+            *description = new_description.clone();
+        }
+        if let Some(active) = self.active {
+            attributes.set(BootEntryAttributes::LOAD_OPTION_ACTIVE, active);
+        }
+        if let Some(hidden) = self.hidden {
+            attributes.set(BootEntryAttributes::LOAD_OPTION_HIDDEN, hidden);
+        }
+        if let Some(category) = self.category {
+            attributes.set(
+                BootEntryAttributes::LOAD_OPTION_CATEGORY_APP,
+                category == BootCategory::App,
+            );
+        }
+        previous != (description.clone(), *attributes)
+    }
+}
+
+fn describe_attributes(attributes: BootEntryAttributes) -> String {
+    let mut flags = Vec::new();
+    flags.push(
+        if attributes.contains(BootEntryAttributes::LOAD_OPTION_ACTIVE) {
+            "active"
+        } else {
+            "inactive"
+        },
+    );
+    if attributes.contains(BootEntryAttributes::LOAD_OPTION_HIDDEN) {
+        flags.push("hidden");
+    }
+    if attributes.contains(BootEntryAttributes::LOAD_OPTION_CATEGORY_APP) {
+        flags.push("app");
+    }
+    flags.join(", ")
+}
+
+/// attributes of a raw `EFI_LOAD_OPTION`
+pub fn load_option_attributes(data: &[u8]) -> BootEntryAttributes {
+    let attributes = data
+        .get(0..4)
+        .and_then(|attributes| attributes.try_into().ok())
+        .map(u32::from_le_bytes)
+        .unwrap_or(0);
+    BootEntryAttributes::from_bits_retain(attributes)
+}
+
+/// description of a raw `EFI_LOAD_OPTION`, the null terminated UCS-2 string following the
+/// attributes and the length of the device path list
+pub fn load_option_description(data: &[u8]) -> String {
+    let chars: Vec<u16> = data
+        .get(6..)
+        .unwrap_or_default()
+        .chunks_exact(2)
+        .map(|char| u16::from_le_bytes([char[0], char[1]]))
+        .take_while(|char| *char != 0)
+        .collect();
+    String::from_utf16_lossy(&chars)
+}
+
+/// replace attributes and description of a raw `EFI_LOAD_OPTION`, keeping the device path list
+/// and optional data byte for byte
+fn patch_load_option(data: &[u8], attributes: u32, description: &str) -> Option<Vec<u8>> {
+    let description_len = data
+        .get(6..)?
+        .chunks_exact(2)
+        .position(|char| char == [0, 0])?;
+    let rest = data.get(6 + (description_len + 1) * 2..)?;
+    let mut patched = attributes.to_le_bytes().to_vec();
+    patched.extend_from_slice(data.get(4..6)?);
+    for char in description.encode_utf16().chain([0]) {
+        patched.extend_from_slice(&char.to_le_bytes());
+    }
+    patched.extend_from_slice(rest);
+    Some(patched)
+}
+
+/// change the description and attributes of the boot entry `id`
+///
+/// The variable data is patched in place, device paths efivar cannot encode again like network
+/// or usb boot are kept unchanged.
+pub fn edit_boot_entry(id: u16, edit: EntryEdit, dry_run: bool) -> Result<(), ManagerError> {
+    let (data, flags) = read_boot_variable(id)?;
+    let mut description = load_option_description(&data);
+    let mut attributes = load_option_attributes(&data);
+    if !edit.apply(&mut description, &mut attributes) {
+        println!("Boot entry {id:04X} is already up to date.");
+        return Ok(());
+    }
+    let summary = format!(
+        "boot entry {id:04X} to `{description}` ({})",
+        describe_attributes(attributes)
+    );
+    if dry_run {
+        println!("Would change {summary}");
+        return Ok(());
+    }
+    let Some(patched) = patch_load_option(&data, attributes.bits(), &description) else {
+        eprintln!("Boot entry {id:04X} has malformed variable data, leaving it unchanged.");
+        return Ok(());
+    };
+    println!("Changing {summary}");
+    efivar::system().write(&Variable::new(&format!("Boot{id:04X}")), flags, &patched)?;
+    Ok(())
+}
+
+/// delete the boot entry `id` after confirmation
+pub fn delete_boot_entry_by_id(id: u16, yes: bool, dry_run: bool) -> Result<(), ManagerError> {
+    let (data, _) = read_boot_variable(id)?;
+    let description = load_option_description(&data);
+    if dry_run {
+        println!("Would delete boot entry {id:04X} `{description}`");
+        return Ok(());
+    }
+    if !yes
+        && !dialoguer::Confirm::new()
+            .with_prompt(format!("Delete boot entry {id:04X} `{description}`?"))
+            .interact()?
+    {
+        return Ok(());
+    }
+    delete_boot_entry(id)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod boot_order_position_tests {
+    use super::apply_positions;
+
+    #[test]
+    fn apply_positions_test() {
+        assert_eq!(apply_positions(&[1, 2, 3], &[(3, 0)]), vec![3, 1, 2]);
+        assert_eq!(apply_positions(&[1, 2, 3], &[(1, 10)]), vec![2, 3, 1]);
+        assert_eq!(
+            apply_positions(&[1, 2, 3, 4], &[(4, 1), (3, 0)]),
+            vec![3, 4, 1, 2]
+        );
+        assert_eq!(apply_positions(&[1, 2], &[]), vec![1, 2]);
+    }
+}
+
+#[cfg(test)]
+mod load_option_tests {
+    use super::{load_option_attributes, load_option_description, patch_load_option};
+
+    #[test]
+    fn patch_load_option_test() {
+        // attributes, device path length, "PXE", device path end node, optional data
+        let data = [
+            1, 0, 0, 0, 4, 0, b'P', 0, b'X', 0, b'E', 0, 0, 0, 0x7f, 0xff, 4, 0, 0xaa,
+        ];
+        assert_eq!(
+            patch_load_option(&data, 9, "Net").unwrap(),
+            vec![9, 0, 0, 0, 4, 0, b'N', 0, b'e', 0, b't', 0, 0, 0, 0x7f, 0xff, 4, 0, 0xaa]
+        );
+        assert_eq!(
+            patch_load_option(&data, 1, "").unwrap(),
+            vec![1, 0, 0, 0, 4, 0, 0, 0, 0x7f, 0xff, 4, 0, 0xaa]
+        );
+        assert!(patch_load_option(&data[..10], 1, "PXE").is_none());
+        assert_eq!(load_option_description(&data), "PXE");
+        assert_eq!(load_option_description(&[1, 0]), "");
+        assert_eq!(load_option_attributes(&data).bits(), 1);
+    }
+}
@@ -0,0 +1,471 @@
+//! Non-interactive boot order control
+//!
+//! Boot entries are selected by their hex id, their exact description or the path of the image
+//! they boot, so scripts do not need to know the ids assigned by the firmware.
+//!
+//! A boot order policy sorts the entries in the boot order into groups: entries of the primary
+//! images of the build mappings, entries of retained previous images, configured fallback
+//! entries and all other entries. Groups are placed in the configured order, images newest
+//! kernel first, fallbacks in their configured order and other entries keep their relative order.
+use std::path::{Path, PathBuf};
+
+use efivar::{boot::BootEntryAttributes, efi::Variable};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    boot_assessment,
+    boot_entries::{load_option_attributes, load_option_description},
+    boot_entry_file,
+    error::ManagerError,
+    find_efi_partition, firmware, get_ranked_images, parse_boot_id, EfiStubBuildConfig,
+};
+
+/// a firmware boot entry as listed by `bootorder --json`
+#[derive(Debug, Clone, Serialize)]
+pub struct BootEntryInfo {
+    #[serde(serialize_with = "serialize_boot_id")]
+    pub id: u16,
+    pub description: String,
+    pub active: bool,
+    /// image relative to the root of its partition, unset for entries not booting a file
+    pub image: Option<PathBuf>,
+    /// index in the boot order, unset for entries not in the boot order
+    pub position: Option<usize>,
+}
+
+fn serialize_boot_id<S: serde::Serializer>(id: &u16, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{id:04X}"))
+}
+
+/// read all firmware boot entries, entries in the boot order first in their order
+///
+/// Entries efivar cannot decode are described by their raw variable data and have no image.
+pub fn read_boot_entries() -> Result<(Vec<u16>, Vec<BootEntryInfo>), efivar::Error> {
+    let boot_order = efivar::system().get_boot_order()?;
+    let position = |id| boot_order.iter().position(|boot_id| *boot_id == id);
+    let mut entries = Vec::new();
+    for (boot_var, variable) in efivar::system().get_boot_entries()? {
+        match boot_var {
+            Ok(boot_var) => entries.push(BootEntryInfo {
+                id: boot_var.id,
+                position: position(boot_var.id),
+                active: boot_var
+                    .entry
+                    .attributes
+                    .contains(BootEntryAttributes::LOAD_OPTION_ACTIVE),
+                image: boot_var.entry.file_path_list.as_ref().map(boot_entry_file),
+                description: boot_var.entry.description,
+            }),
+            Err(_) => {
+                let Ok(id) = parse_boot_id(variable.name()) else {
+                    continue;
+                };
+                let data = efivar::system()
+                    .read(&variable)
+                    .map(|(data, _)| data)
+                    .unwrap_or_default();
+                entries.push(BootEntryInfo {
+                    id,
+                    position: position(id),
+                    active: load_option_attributes(&data)
+                        .contains(BootEntryAttributes::LOAD_OPTION_ACTIVE),
+                    image: None,
+                    description: load_option_description(&data),
+                });
+            }
+        }
+    }
+    entries.sort_by_key(|entry| (entry.position.is_none(), entry.position, entry.id));
+    Ok((boot_order, entries))
+}
+
+/// check if `selector` is a trailing part of the image path of `entry`, compared
+/// case-insensitively as on FAT
+fn image_matches(entry: &BootEntryInfo, selector: &str) -> bool {
+    let selector_path = PathBuf::from(
+        selector
+            .replace('\\', "/")
+            .trim_start_matches('/')
+            .to_lowercase(),
+    );
+    entry.image.as_ref().is_some_and(|image| {
+        !selector_path.as_os_str().is_empty()
+            && Path::new(&image.to_string_lossy().to_lowercase()).ends_with(&selector_path)
+    })
+}
+
+/// find the boot entry selected by an id, a description or an image path
+///
+/// Ids take precedence over descriptions, descriptions over images.
+pub fn resolve_selector(entries: &[BootEntryInfo], selector: &str) -> Result<u16, ManagerError> {
+    if let Ok(id) = parse_boot_id(selector) {
+        if entries.iter().any(|entry| entry.id == id) {
+            return Ok(id);
+        }
+    }
+    let by_description: Vec<u16> = entries
+        .iter()
+        .filter(|entry| entry.description == selector)
+        .map(|entry| entry.id)
+        .collect();
+    let matching = if by_description.is_empty() {
+        entries
+            .iter()
+            .filter(|entry| image_matches(entry, selector))
+            .map(|entry| entry.id)
+            .collect()
+    } else {
+        by_description
+    };
+    match matching.as_slice() {
+        [] => Err(ManagerError::BootEntryNotFound(selector.to_string())),
+        [id] => Ok(*id),
+        _ => Err(ManagerError::AmbiguousBootEntry(selector.to_string())),
+    }
+}
+
+pub fn format_boot_order(order: &[u16]) -> String {
+    order
+        .iter()
+        .map(|id| format!("{id:04X}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// write `new_order` if it differs from `boot_order`
+pub fn set_boot_order(
+    boot_order: &[u16],
+    new_order: Vec<u16>,
+    dry_run: bool,
+) -> Result<(), ManagerError> {
+    if new_order == boot_order {
+        println!("Boot order is already {}", format_boot_order(boot_order));
+    } else if dry_run {
+        println!(
+            "Would change boot order from {} to {}",
+            format_boot_order(boot_order),
+            format_boot_order(&new_order)
+        );
+    } else {
+        println!(
+            "Changing boot order from {} to {}",
+            format_boot_order(boot_order),
+            format_boot_order(&new_order)
+        );
+        efivar::system().set_boot_order(new_order)?;
+    }
+    Ok(())
+}
+
+/// move the selected entry to the front of the boot order
+pub fn boot_first(selector: &str, dry_run: bool) -> Result<(), ManagerError> {
+    let (boot_order, entries) = read_boot_entries()?;
+    let id = resolve_selector(&entries, selector)?;
+    let mut new_order = vec![id];
+    new_order.extend(boot_order.iter().copied().filter(|boot_id| *boot_id != id));
+    set_boot_order(&boot_order, new_order, dry_run)
+}
+
+/// replace the boot order, all ids have to exist
+pub fn replace_boot_order(ids: Vec<u16>, dry_run: bool) -> Result<(), ManagerError> {
+    let (boot_order, entries) = read_boot_entries()?;
+    if let Some(missing) = ids
+        .iter()
+        .find(|id| !entries.iter().any(|entry| entry.id == **id))
+    {
+        return Err(ManagerError::BootEntryNotFound(format!("{missing:04X}")));
+    }
+    set_boot_order(&boot_order, ids, dry_run)
+}
+
+/// remove the selected entry from the boot order, the entry itself is kept
+pub fn remove_from_boot_order(selector: &str, dry_run: bool) -> Result<(), ManagerError> {
+    let (boot_order, entries) = read_boot_entries()?;
+    let id = resolve_selector(&entries, selector)?;
+    let new_order = boot_order
+        .iter()
+        .copied()
+        .filter(|boot_id| *boot_id != id)
+        .collect();
+    set_boot_order(&boot_order, new_order, dry_run)
+}
+
+/// boot the entry `id` once on the next boot without changing the boot order
+pub fn set_boot_next(id: u16) -> Result<(), efivar::Error> {
+    efivar::system().write(
+        &Variable::new("BootNext"),
+        firmware::variable_flags(),
+        &id.to_le_bytes(),
+    )
+}
+
+/// set `BootNext` to the selected entry and optionally reboot right away
+pub fn reboot_into(selector: &str, reboot: bool, dry_run: bool) -> Result<(), ManagerError> {
+    let (_, entries) = read_boot_entries()?;
+    let id = resolve_selector(&entries, selector)?;
+    let description = &entries
+        .iter()
+        .find(|entry| entry.id == id)
+        .unwrap()
+        .description;
+    if dry_run {
+        println!("Would boot entry {id:04X} `{description}` once on the next boot");
+        if reboot {
+            println!("Would reboot now");
+        }
+        return Ok(());
+    }
+    set_boot_next(id)?;
+    println!("Booting entry {id:04X} `{description}` once on the next boot");
+    if reboot {
+        firmware::reboot();
+    }
+    Ok(())
+}
+
+/// print all boot entries as json
+pub fn print_boot_entries_json() -> Result<(), ManagerError> {
+    let (_, entries) = read_boot_entries()?;
+    println!("{}", serde_json::to_string_pretty(&entries).unwrap());
+    Ok(())
+}
+
+/// group of boot entries in a boot order policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderGroup {
+    /// entries of the primary images of the build mappings
+    Primary,
+    /// entries of retained images of previous kernel versions
+    Previous,
+    /// entries matching the `fallback` selectors
+    Fallback,
+    /// all remaining entries
+    Other,
+}
+
+fn default_groups() -> Vec<OrderGroup> {
+    vec![
+        OrderGroup::Primary,
+        OrderGroup::Previous,
+        OrderGroup::Fallback,
+        OrderGroup::Other,
+    ]
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BootOrderPolicy {
+    /// order of the groups, entries of groups not listed are placed last
+    #[serde(default = "default_groups")]
+    pub groups: Vec<OrderGroup>,
+    /// ids, descriptions or images of fallback entries in their preferred order
+    #[serde(default)]
+    pub fallback: Vec<String>,
+}
+
+/// images of the build mappings relative to the root of the efi partition, newest first
+pub struct RankedImages {
+    pub primary: Vec<PathBuf>,
+    pub previous: Vec<PathBuf>,
+}
+
+impl RankedImages {
+    fn rank(images: &[PathBuf], entry: &BootEntryInfo) -> Option<usize> {
+        let image = entry.image.as_ref()?.to_string_lossy().to_lowercase();
+        images
+            .iter()
+            .position(|ranked| ranked.to_string_lossy().to_lowercase() == image)
+    }
+}
+
+impl BootOrderPolicy {
+    /// position of `group` in the configured order, groups not listed are placed last
+    fn group_position(&self, group: OrderGroup) -> usize {
+        self.groups
+            .iter()
+            .position(|g| *g == group)
+            .unwrap_or(self.groups.len())
+    }
+
+    /// sort key of an entry: group position, rank inside the group
+    fn sort_key(&self, images: &RankedImages, entry: &BootEntryInfo) -> (usize, usize) {
+        let fallback_rank = self.fallback.iter().position(|selector| {
+            parse_boot_id(selector).is_ok_and(|id| id == entry.id)
+                || entry.description == *selector
+                || image_matches(entry, selector)
+        });
+        let (group, rank) = if let Some(rank) = RankedImages::rank(&images.primary, entry) {
+            (OrderGroup::Primary, rank)
+        } else if let Some(rank) = RankedImages::rank(&images.previous, entry) {
+            (OrderGroup::Previous, rank)
+        } else if let Some(rank) = fallback_rank {
+            (OrderGroup::Fallback, rank)
+        } else {
+            (OrderGroup::Other, 0)
+        };
+        (self.group_position(group), rank)
+    }
+
+    /// sort key of the entry `id`, ids without an entry stay in place among the other entries
+    fn id_sort_key(
+        &self,
+        images: &RankedImages,
+        entries: &[BootEntryInfo],
+        id: u16,
+    ) -> (usize, usize) {
+        entries
+            .iter()
+            .find(|entry| entry.id == id)
+            .map(|entry| self.sort_key(images, entry))
+            .unwrap_or((self.group_position(OrderGroup::Other), 0))
+    }
+
+    /// boot order with the entries of `boot_order` sorted by the policy
+    ///
+    /// The sort is stable, so entries with equal keys keep their relative order.
+    pub fn order(
+        &self,
+        images: &RankedImages,
+        boot_order: &[u16],
+        entries: &[BootEntryInfo],
+    ) -> Vec<u16> {
+        let mut new_order = boot_order.to_vec();
+        new_order.sort_by_key(|id| self.id_sort_key(images, entries, *id));
+        new_order
+    }
+}
+
+/// position in the boot order a new entry for `image` is inserted at according to the policy
+pub fn insert_position(
+    policy: &BootOrderPolicy,
+    images: &RankedImages,
+    image: &Path,
+) -> Result<usize, ManagerError> {
+    let (boot_order, entries) = read_boot_entries()?;
+    let new_entry = BootEntryInfo {
+        id: u16::MAX,
+        description: String::new(),
+        active: true,
+        image: Some(image.to_path_buf()),
+        position: None,
+    };
+    let key = policy.sort_key(images, &new_entry);
+    // entries after which the new one is sorted, the sort is stable so equal keys come first
+    let position = boot_order
+        .iter()
+        .filter(|id| policy.id_sort_key(images, &entries, **id) <= key)
+        .count();
+    Ok(position)
+}
+
+/// sort the boot order according to the policy
+pub fn apply_policy(
+    settings: &EfiStubBuildConfig,
+    policy: &BootOrderPolicy,
+    dry_run: bool,
+) -> Result<(), ManagerError> {
+    let Some((_, mount_dir)) = find_efi_partition(Path::new(&settings.efi_dir)) else {
+        eprintln!(
+            "{} is not on a mounted efi partition, the boot order was not changed.",
+            settings.efi_dir
+        );
+        return Ok(());
+    };
+    let images = get_ranked_images(settings, &mount_dir);
+    let (boot_order, entries) = read_boot_entries()?;
+    let mut new_order = policy.order(&images, &boot_order, &entries);
+    // an entry waiting for confirmation must not replace the current first entry
+    if let Some(trial) = boot_assessment::pending_trial() {
+        if new_order.first() == Some(&trial.id) {
+            new_order = boot_assessment::demote(&new_order, trial.id);
+        }
+    }
+    set_boot_order(&boot_order, new_order, dry_run)
+}
+
+#[cfg(test)]
+mod boot_entry_selector_tests {
+    use std::path::PathBuf;
+
+    use super::{resolve_selector, BootEntryInfo, BootOrderPolicy, OrderGroup, RankedImages};
+
+    fn entry(id: u16, description: &str, image: Option<&str>) -> BootEntryInfo {
+        BootEntryInfo {
+            id,
+            description: description.to_string(),
+            active: true,
+            image: image.map(PathBuf::from),
+            position: None,
+        }
+    }
+
+    #[test]
+    fn resolve_selector_test() {
+        let entries = [
+            entry(0x1, "Arch Linux", Some("EFI/Arch/ArchLinux.efi")),
+            entry(0x2, "Arch Linux (LTS)", Some("EFI/Arch/ArchLinuxLts.efi")),
+            entry(0x3, "UEFI Shell", Some("EFI/Shell/ArchLinux.efi")),
+            entry(0xa, "PXE", None),
+        ];
+        assert_eq!(resolve_selector(&entries, "0002").unwrap(), 0x2);
+        assert_eq!(resolve_selector(&entries, "Boot000A").unwrap(), 0xa);
+        assert_eq!(resolve_selector(&entries, "Arch Linux").unwrap(), 0x1);
+        assert_eq!(resolve_selector(&entries, "ArchLinuxLts.efi").unwrap(), 0x2);
+        assert_eq!(
+            resolve_selector(&entries, "\\EFI\\ARCH\\ArchLinux.efi").unwrap(),
+            0x1
+        );
+        assert!(resolve_selector(&entries, "ArchLinux.efi").is_err());
+        assert!(resolve_selector(&entries, "missing").is_err());
+    }
+
+    #[test]
+    fn boot_order_policy_test() {
+        let entries = [
+            entry(0x1, "Arch Linux", Some("ArchLinux.efi")),
+            entry(0x2, "Arch Linux (LTS)", Some("ArchLinuxLts.efi")),
+            entry(0x3, "UEFI Shell", Some("EFI/Shell/Shell.efi")),
+            entry(
+                0x4,
+                "Arch Linux previous",
+                Some("ArchLinux-6.9.1-arch1-1.efi"),
+            ),
+            entry(
+                0x5,
+                "Windows Boot Manager",
+                Some("EFI/Microsoft/bootmgfw.efi"),
+            ),
+            entry(0x6, "PXE", None),
+        ];
+        let images = RankedImages {
+            primary: vec!["ArchLinux.efi".into(), "ArchLinuxLts.efi".into()],
+            previous: vec!["ArchLinux-6.9.1-arch1-1.efi".into()],
+        };
+        let policy = BootOrderPolicy {
+            groups: vec![
+                OrderGroup::Primary,
+                OrderGroup::Previous,
+                OrderGroup::Fallback,
+                OrderGroup::Other,
+            ],
+            fallback: vec!["UEFI Shell".to_string()],
+        };
+        assert_eq!(
+            policy.order(&images, &[6, 5, 4, 3, 2, 1], &entries),
+            vec![1, 2, 4, 3, 6, 5]
+        );
+        // 7 has no entry and keeps its place among the other entries
+        assert_eq!(
+            policy.order(&images, &[6, 7, 5, 4, 3, 2, 1], &entries),
+            vec![1, 2, 4, 3, 6, 7, 5]
+        );
+        let policy = BootOrderPolicy {
+            groups: vec![OrderGroup::Fallback, OrderGroup::Primary],
+            fallback: vec!["0005".to_string()],
+        };
+        assert_eq!(
+            policy.order(&images, &[6, 5, 4, 3, 2, 1], &entries),
+            vec![5, 1, 2, 6, 4, 3]
+        );
+    }
+}
@@ -0,0 +1,235 @@
+//! File names of the efi binaries
+//!
+//! Destinations in the build mappings are templates that may contain the placeholders
+//! `{flavour}`, `{version}`, `{machine_id}` and `{variant}`. Files are recognised as owned by a
+//! mapping by matching them against its template, so that images of old versions can be cleaned
+//! up even though their exact names are not known anymore.
+//!
+//! Images placed in the Boot Loader Specification layout may carry a boot counter like
+//! `+3` or `+2-1` in front of the extension, which loaders rename while counting boot attempts.
+//! Such files are treated as the same image as the one without counter.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use regex::Regex;
+
+/// values of all placeholders that do not depend on the kernel version
+pub struct TemplateValues<'a> {
+    pub flavour: &'a str,
+    pub machine_id: &'a str,
+    pub variant: &'a str,
+}
+
+const VERSION_PLACEHOLDER: &str = "{version}";
+
+/// destination of all images in the Boot Loader Specification type #2 layout
+pub const BLS_TEMPLATE: &str = "EFI/Linux/{machine_id}-{version}.efi";
+
+/// matches the boot counter in front of the extension of a file name
+const BOOT_COUNTER_PATTERN: &str = r"\+[0-9]+(-[0-9]+)?";
+
+/// fill in all placeholders except `{version}`
+fn render_partial(template: &str, values: &TemplateValues) -> String {
+    template
+        .replace("{flavour}", values.flavour)
+        .replace("{machine_id}", values.machine_id)
+        .replace("{variant}", values.variant)
+}
+
+/// render the destination of the efi binary for kernel `version`
+pub fn render(template: &str, values: &TemplateValues, version: &str) -> String {
+    render_partial(template, values).replace(VERSION_PLACEHOLDER, version)
+}
+
+/// template used for images of retained previous kernel versions
+///
+/// Templates containing `{version}` already produce distinct names for every version, for all
+/// others `-{version}` is appended to the file stem, `ArchLinuxZen.efi` becomes
+/// `ArchLinuxZen-{version}.efi`.
+pub fn retained_template(template: &str) -> String {
+    if template.contains(VERSION_PLACEHOLDER) {
+        return template.to_string();
+    }
+    let path = Path::new(template);
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(ext) => format!("{stem}-{VERSION_PLACEHOLDER}.{}", ext.to_string_lossy()),
+        None => format!("{stem}-{VERSION_PLACEHOLDER}"),
+    };
+    match path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        Some(parent) => parent.join(file_name).to_string_lossy().to_string(),
+        None => file_name,
+    }
+}
+
+/// regex matching the file names produced by the file name part of `template`
+///
+/// Versions always start with a digit, which keeps `ArchLinux-{version}.efi` from matching the
+/// images of a mapping named `ArchLinux-Lts.efi`.
+fn file_name_regex(template: &str, values: &TemplateValues) -> Regex {
+    let rendered = render_partial(template, values);
+    let file_name = Path::new(&rendered)
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let (stem, ext) = match file_name.rfind('.') {
+        Some(pos) => file_name.split_at(pos),
+        None => (file_name.as_str(), ""),
+    };
+    let pattern = stem
+        .split(VERSION_PLACEHOLDER)
+        .map(regex::escape)
+        .collect::<Vec<_>>()
+        .join(r"([0-9][^/]*)");
+    Regex::new(&format!(
+        "^{pattern}({BOOT_COUNTER_PATTERN})?{}$",
+        regex::escape(ext)
+    ))
+    .unwrap()
+}
+
+/// kernel version a file created from `template` is named after, `None` for other files and
+/// templates without `{version}`
+pub fn file_version(template: &str, values: &TemplateValues, path: &Path) -> Option<String> {
+    let file_name = strip_boot_counter(path)
+        .file_name()?
+        .to_string_lossy()
+        .to_string();
+    let captures = file_name_regex(template, values).captures(&file_name)?;
+    Some(captures.get(1)?.as_str().to_string())
+}
+
+/// add a boot counter with `tries` boot attempts to the file name of `path`
+pub fn with_boot_counter(path: &Path, tries: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(ext) => format!("{stem}+{tries}.{}", ext.to_string_lossy()),
+        None => format!("{stem}+{tries}"),
+    };
+    path.with_file_name(file_name)
+}
+
+/// remove a boot counter from the file name of `path`
+pub fn strip_boot_counter(path: &Path) -> PathBuf {
+    let regex = Regex::new(&format!(r"{BOOT_COUNTER_PATTERN}(\.[^.]*)?$")).unwrap();
+    let file_name = path.file_name().unwrap().to_string_lossy();
+    path.with_file_name(regex.replace(&file_name, "$2").to_string())
+}
+
+/// list existing files that are the image at `path` with or without any boot counter
+pub fn boot_counter_variants(path: &Path) -> Vec<PathBuf> {
+    let image = strip_boot_counter(path);
+    let mut variants = Vec::new();
+    if let Some(Ok(entries)) = path.parent().map(fs::read_dir) {
+        for entry in entries.flatten() {
+            if strip_boot_counter(&entry.path()) == image {
+                variants.push(entry.path());
+            }
+        }
+    }
+    variants
+}
+
+/// list all files inside `efi_dir` that were created from `template`
+///
+/// The directory part of the template must not contain `{version}`.
+pub fn find_owned_files(efi_dir: &Path, template: &str, values: &TemplateValues) -> Vec<PathBuf> {
+    let rendered = render_partial(template, values);
+    let directory = match Path::new(&rendered).parent() {
+        Some(parent) => efi_dir.join(parent),
+        None => efi_dir.to_path_buf(),
+    };
+    let regex = file_name_regex(template, values);
+    let mut owned_files = Vec::new();
+    if let Ok(entries) = fs::read_dir(directory) {
+        for entry in entries.flatten() {
+            if entry.path().is_file() && regex.is_match(&entry.file_name().to_string_lossy()) {
+                owned_files.push(entry.path());
+            }
+        }
+    }
+    owned_files
+}
+
+#[cfg(test)]
+mod destination_template_tests {
+    use std::path::Path;
+
+    use super::{
+        file_name_regex, file_version, render, retained_template, strip_boot_counter,
+        with_boot_counter, TemplateValues, BLS_TEMPLATE,
+    };
+
+    #[test]
+    fn destination_template_test() {
+        let values = TemplateValues {
+            flavour: "zen",
+            machine_id: "4b1d",
+            variant: "",
+        };
+        assert_eq!(
+            render(
+                "ArchLinux-{flavour}-{version}.efi",
+                &values,
+                "6.9.1-zen1-1-zen"
+            ),
+            "ArchLinux-zen-6.9.1-zen1-1-zen.efi"
+        );
+        assert_eq!(
+            retained_template("ArchLinuxZen.efi"),
+            "ArchLinuxZen-{version}.efi"
+        );
+        assert_eq!(
+            retained_template("{machine_id}/ArchLinux.efi"),
+            "{machine_id}/ArchLinux-{version}.efi"
+        );
+        assert_eq!(
+            retained_template("ArchLinux-{version}.efi"),
+            "ArchLinux-{version}.efi"
+        );
+
+        let regex = file_name_regex("ArchLinux-{version}.efi", &values);
+        assert!(regex.is_match("ArchLinux-6.9.1-arch1-1.efi"));
+        assert!(!regex.is_match("ArchLinux-Lts-6.6.30-1-lts.efi"));
+        assert!(!regex.is_match("ArchLinux.efi"));
+        assert!(file_name_regex("ArchLinux.efi", &values).is_match("ArchLinux.efi"));
+    }
+
+    #[test]
+    fn boot_counter_test() {
+        let values = TemplateValues {
+            flavour: "arch",
+            machine_id: "4b1d",
+            variant: "",
+        };
+        let image = render(BLS_TEMPLATE, &values, "6.9.1-arch1-1");
+        assert_eq!(image, "EFI/Linux/4b1d-6.9.1-arch1-1.efi");
+        let counted = with_boot_counter(Path::new(&image), 3);
+        assert_eq!(counted, Path::new("EFI/Linux/4b1d-6.9.1-arch1-1+3.efi"));
+        assert_eq!(
+            strip_boot_counter(Path::new("EFI/Linux/4b1d-6.9.1-arch1-1+2-1.efi")),
+            Path::new(&image)
+        );
+        assert_eq!(strip_boot_counter(Path::new(&image)), Path::new(&image));
+
+        assert_eq!(
+            file_version(
+                BLS_TEMPLATE,
+                &values,
+                Path::new("4b1d-6.9.1-arch1-1+2-1.efi")
+            ),
+            Some("6.9.1-arch1-1".to_string())
+        );
+        assert_eq!(
+            file_version("ArchLinux.efi", &values, Path::new("ArchLinux.efi")),
+            None
+        );
+
+        let regex = file_name_regex(BLS_TEMPLATE, &values);
+        assert!(regex.is_match("4b1d-6.9.1-arch1-1.efi"));
+        assert!(regex.is_match("4b1d-6.9.1-arch1-1+2-1.efi"));
+        assert!(!regex.is_match("ffff-6.9.1-arch1-1.efi"));
+    }
+}
@@ -0,0 +1,223 @@
+//! Inspection of unified kernel images produced by dracut
+//!
+//! Only the PE section table is parsed, which is all that is needed to verify that an image
+//! contains a kernel, an initramfs and the expected metadata.
+use std::{collections::BTreeMap, fmt::Display, fs, io, path::Path};
+
+/// sections every unified kernel image built by dracut has to contain
+pub const REQUIRED_SECTIONS: [&str; 4] = [".linux", ".initrd", ".cmdline", ".osrel"];
+
+#[derive(Debug)]
+pub enum ValidationError {
+    Io(io::Error),
+    NotPeFile,
+    MissingSection(&'static str),
+    UnameMismatch { expected: String, found: String },
+    CmdlineMismatch { expected: String, found: String },
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::Io(err) => write!(f, "could not read image: {err}"),
+            ValidationError::NotPeFile => f.write_str("image is not a valid PE file"),
+            ValidationError::MissingSection(section) => {
+                write!(f, "image is missing the `{section}` section")
+            }
+            ValidationError::UnameMismatch { expected, found } => {
+                write!(f, "image contains kernel `{found}` instead of `{expected}`")
+            }
+            ValidationError::CmdlineMismatch { expected, found } => {
+                write!(
+                    f,
+                    "image cmdline `{found}` does not match configured `{expected}`"
+                )
+            }
+        }
+    }
+}
+
+impl From<io::Error> for ValidationError {
+    fn from(err: io::Error) -> Self {
+        ValidationError::Io(err)
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// parse the section table of a PE file and return the contents of all sections by name
+pub fn parse_pe_sections(data: &[u8]) -> Option<BTreeMap<String, Vec<u8>>> {
+    if data.get(0..2)? != b"MZ" {
+        return None;
+    }
+    let pe_offset = read_u32(data, 0x3c)? as usize;
+    if data.get(pe_offset..pe_offset + 4)? != b"PE\0\0" {
+        return None;
+    }
+    let coff_header = pe_offset + 4;
+    let number_of_sections = read_u16(data, coff_header + 2)? as usize;
+    let optional_header_size = read_u16(data, coff_header + 16)? as usize;
+    let section_table = coff_header + 20 + optional_header_size;
+
+    let mut sections = BTreeMap::new();
+    for nr in 0..number_of_sections {
+        let header = section_table + nr * 40;
+        let name = data.get(header..header + 8)?;
+        let name = String::from_utf8_lossy(name)
+            .trim_end_matches('\0')
+            .to_string();
+        let virtual_size = read_u32(data, header + 8)? as usize;
+        let raw_size = read_u32(data, header + 16)? as usize;
+        let raw_offset = read_u32(data, header + 20)? as usize;
+        // the raw data is padded to the file alignment, the virtual size is the actual size
+        let size = if virtual_size != 0 {
+            virtual_size.min(raw_size)
+        } else {
+            raw_size
+        };
+        let content = data.get(raw_offset..raw_offset + size)?.to_vec();
+        sections.insert(name, content);
+    }
+    Some(sections)
+}
+
+fn section_text(content: &[u8]) -> String {
+    String::from_utf8_lossy(content)
+        .trim_matches(|c: char| c == '\0' || c.is_whitespace())
+        .to_string()
+}
+
+/// size of the initramfs embedded in the unified kernel image at `path`
+pub fn embedded_initramfs_size(path: &Path) -> Option<u64> {
+    let data = fs::read(path).ok()?;
+    let sections = parse_pe_sections(&data)?;
+    sections.get(".initrd").map(|initrd| initrd.len() as u64)
+}
+
+/// verify that the image at `path` is a complete unified kernel image for kernel `version`
+///
+/// The `.uname` section is only added by recent dracut versions, so it is checked when present.
+/// The cmdline is only compared when one is configured.
+pub fn validate_efi_binary(
+    path: &Path,
+    version: &str,
+    cmdline: Option<&str>,
+) -> Result<(), ValidationError> {
+    let data = fs::read(path)?;
+    let sections = parse_pe_sections(&data).ok_or(ValidationError::NotPeFile)?;
+    for section in REQUIRED_SECTIONS {
+        if !sections.contains_key(section) {
+            return Err(ValidationError::MissingSection(section));
+        }
+    }
+    if let Some(uname) = sections.get(".uname") {
+        let found = section_text(uname);
+        if found != version {
+            return Err(ValidationError::UnameMismatch {
+                expected: version.to_string(),
+                found,
+            });
+        }
+    }
+    if let Some(expected) = cmdline {
+        let found = section_text(sections.get(".cmdline").unwrap());
+        if found != expected.trim() {
+            return Err(ValidationError::CmdlineMismatch {
+                expected: expected.trim().to_string(),
+                found,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod pe_parser_tests {
+    use std::fs;
+
+    use super::{parse_pe_sections, validate_efi_binary, ValidationError};
+
+    type Sections<'a> = &'a [(&'a str, &'a [u8])];
+
+    /// sections, kernel version, configured cmdline and the expected failure
+    type ValidationCase<'a> = (Sections<'a>, &'a str, Option<&'a str>, Result<(), &'a str>);
+
+    fn build_pe(sections: Sections) -> Vec<u8> {
+        let pe_offset = 0x40;
+        let section_table = pe_offset + 24;
+        let mut data_offset = section_table + sections.len() * 40;
+        let mut image = vec![0u8; data_offset];
+        image[0..2].copy_from_slice(b"MZ");
+        image[0x3c..0x40].copy_from_slice(&(pe_offset as u32).to_le_bytes());
+        image[pe_offset..pe_offset + 4].copy_from_slice(b"PE\0\0");
+        image[pe_offset + 6..pe_offset + 8].copy_from_slice(&(sections.len() as u16).to_le_bytes());
+        for (nr, (name, content)) in sections.iter().enumerate() {
+            let header = section_table + nr * 40;
+            image[header..header + name.len()].copy_from_slice(name.as_bytes());
+            let size = (content.len() as u32).to_le_bytes();
+            image[header + 8..header + 12].copy_from_slice(&size);
+            image[header + 16..header + 20].copy_from_slice(&size);
+            image[header + 20..header + 24].copy_from_slice(&(data_offset as u32).to_le_bytes());
+            data_offset += content.len();
+        }
+        for (_, content) in sections {
+            image.extend_from_slice(content);
+        }
+        image
+    }
+
+    #[test]
+    fn parse_sections_test() {
+        let image = build_pe(&[(".osrel", b"ID=arch\n"), (".uname", b"6.9.1-arch1-1\0")]);
+        let sections = parse_pe_sections(&image).unwrap();
+        assert_eq!(sections.get(".osrel").unwrap(), b"ID=arch\n");
+        assert_eq!(sections.get(".uname").unwrap(), b"6.9.1-arch1-1\0");
+        assert!(parse_pe_sections(b"not a pe file").is_none());
+    }
+
+    #[test]
+    fn validate_efi_binary_test() {
+        let complete: [(&str, &[u8]); 5] = [
+            (".linux", b"kernel"),
+            (".initrd", b"initramfs"),
+            (".cmdline", b"root=zfs:AUTO rw\0"),
+            (".osrel", b"ID=arch\n"),
+            (".uname", b"6.9.1-arch1-1\0"),
+        ];
+        let cases: [ValidationCase; 5] = [
+            (&complete, "6.9.1-arch1-1", Some("root=zfs:AUTO rw"), Ok(())),
+            (&complete, "6.9.1-arch1-1", None, Ok(())),
+            (&complete[1..], "6.9.1-arch1-1", None, Err(".linux")),
+            (&complete, "6.9.2-arch1-1", None, Err("uname")),
+            (&complete, "6.9.1-arch1-1", Some("rw"), Err("cmdline")),
+        ];
+        let path = std::env::temp_dir().join(format!("validate_efi_{}.efi", std::process::id()));
+        for (sections, version, cmdline, expected) in cases {
+            fs::write(&path, build_pe(sections)).unwrap();
+            let result = validate_efi_binary(&path, version, cmdline);
+            match (expected, result) {
+                (Ok(()), Ok(())) => {}
+                (Err(".linux"), Err(ValidationError::MissingSection(".linux"))) => {}
+                (Err("uname"), Err(ValidationError::UnameMismatch { expected, found })) => {
+                    assert_eq!(
+                        (expected.as_str(), found.as_str()),
+                        (version, "6.9.1-arch1-1")
+                    );
+                }
+                (Err("cmdline"), Err(ValidationError::CmdlineMismatch { found, .. })) => {
+                    assert_eq!(found, "root=zfs:AUTO rw");
+                }
+                (expected, result) => panic!("expected {expected:?}, got {result:?}"),
+            }
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,113 @@
+//! Errors reported by the commands of the manager
+//!
+//! Every error maps to its own exit code so that callers like the pacman hooks can tell failures
+//! apart from a successful run.
+use std::{fmt::Display, path::PathBuf, process::ExitCode};
+
+use crate::format_size;
+
+#[derive(Debug)]
+pub enum ManagerError {
+    /// the settings file is missing, could not be parsed or is inconsistent
+    Config(config::ConfigError),
+    /// the efi partition does not have enough room for the planned images
+    InsufficientSpace { required: u64, available: u64 },
+    /// dracut failed or produced an invalid image for the listed kernels
+    BuildFailed(Vec<String>),
+    /// the listed files or directories could not be removed
+    CleanFailed(Vec<PathBuf>),
+    /// reading or writing efi variables failed
+    Efi(efivar::Error),
+    /// an interactive prompt could not be shown
+    Prompt(dialoguer::Error),
+    /// the requested kernel is not configured or not installed
+    KernelNotFound(String),
+    /// no firmware boot entry matches the given id, description or image
+    BootEntryNotFound(String),
+    /// more than one firmware boot entry matches the given description or image
+    AmbiguousBootEntry(String),
+    /// the firmware does not support the requested feature
+    FirmwareUnsupported(&'static str),
+    /// an nvram backup could not be written, read or parsed
+    NvramBackup(String),
+}
+
+impl ManagerError {
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from(match self {
+            ManagerError::Config(_) => 1,
+            ManagerError::InsufficientSpace { .. } => 3,
+            ManagerError::BuildFailed(_) => 4,
+            ManagerError::CleanFailed(_) => 5,
+            ManagerError::Efi(_) => 6,
+            ManagerError::Prompt(_) => 7,
+            ManagerError::KernelNotFound(_) => 8,
+            ManagerError::BootEntryNotFound(_) => 9,
+            ManagerError::AmbiguousBootEntry(_) => 10,
+            ManagerError::FirmwareUnsupported(_) => 11,
+            ManagerError::NvramBackup(_) => 12,
+        })
+    }
+}
+
+impl Display for ManagerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ManagerError::Config(err) => write!(f, "Build configuration could not be loaded: {err}"),
+            ManagerError::InsufficientSpace {
+                required,
+                available,
+            } => write!(
+                f,
+                "Not enough free space on the efi partition: building requires about {} but only {} are available. Aborting build!",
+                format_size(*required),
+                format_size(*available)
+            ),
+            ManagerError::BuildFailed(kernels) => {
+                write!(f, "Building efi binaries failed for: {}", kernels.join(", "))
+            }
+            ManagerError::CleanFailed(paths) => write!(
+                f,
+                "Could not remove: {}",
+                paths
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            ManagerError::Efi(err) => write!(f, "Accessing efi variables failed: {err}"),
+            ManagerError::Prompt(err) => write!(f, "Interactive prompt failed: {err}"),
+            ManagerError::KernelNotFound(kernel) => {
+                write!(f, "Kernel {kernel} is not configured or not installed")
+            }
+            ManagerError::BootEntryNotFound(selector) => {
+                write!(f, "No boot entry matches `{selector}`")
+            }
+            ManagerError::AmbiguousBootEntry(selector) => {
+                write!(f, "Multiple boot entries match `{selector}`, select one by its id")
+            }
+            ManagerError::FirmwareUnsupported(feature) => {
+                write!(f, "The firmware does not support {feature}")
+            }
+            ManagerError::NvramBackup(err) => write!(f, "Nvram backup failed: {err}"),
+        }
+    }
+}
+
+impl From<config::ConfigError> for ManagerError {
+    fn from(err: config::ConfigError) -> Self {
+        ManagerError::Config(err)
+    }
+}
+
+impl From<efivar::Error> for ManagerError {
+    fn from(err: efivar::Error) -> Self {
+        ManagerError::Efi(err)
+    }
+}
+
+impl From<dialoguer::Error> for ManagerError {
+    fn from(err: dialoguer::Error) -> Self {
+        ManagerError::Prompt(err)
+    }
+}
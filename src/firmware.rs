@@ -0,0 +1,123 @@
+//! Global firmware boot manager settings
+//!
+//! Without a boot loader the firmware boot menu is the only menu left. `Timeout` controls how
+//! long it is shown before the first entry of the boot order is booted and the boot to firmware
+//! UI bit of `OsIndications` enters the firmware setup on the next boot.
+use std::process::Command;
+
+use efivar::efi::{VarFlags, Variable};
+
+use crate::error::ManagerError;
+
+/// `Timeout` value keeping the firmware boot menu open until an entry is chosen
+const TIMEOUT_WAIT_FOREVER: u16 = 0xFFFF;
+
+/// bit of `OsIndications` requesting the firmware setup on the next boot
+const OS_INDICATIONS_BOOT_TO_FW_UI: u64 = 0x1;
+
+/// attributes of the global boot manager variables written by the manager
+pub fn variable_flags() -> VarFlags {
+    VarFlags::NON_VOLATILE | VarFlags::BOOTSERVICE_ACCESS | VarFlags::RUNTIME_ACCESS
+}
+
+/// reboot the system right away, failures are only reported
+pub fn reboot() {
+    match Command::new("systemctl").arg("reboot").status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("Could not reboot: systemctl exited with {status}"),
+        Err(err) => eprintln!("Could not reboot: {err}"),
+    }
+}
+
+/// read a 16 bit efi variable like `Timeout` or `BootCurrent`
+pub fn read_u16(name: &str) -> Option<u16> {
+    let (value, _) = efivar::system().read(&Variable::new(name)).ok()?;
+    Some(u16::from_le_bytes(value.get(0..2)?.try_into().ok()?))
+}
+
+fn read_u64(name: &str) -> Option<u64> {
+    let (value, _) = efivar::system().read(&Variable::new(name)).ok()?;
+    Some(u64::from_le_bytes(value.get(0..8)?.try_into().ok()?))
+}
+
+fn format_timeout(timeout: u16) -> String {
+    match timeout {
+        TIMEOUT_WAIT_FOREVER => "until an entry is chosen".to_string(),
+        seconds => format!("{seconds} seconds"),
+    }
+}
+
+/// show the firmware boot menu timeout or set it to `seconds`
+pub fn timeout_handler(seconds: Option<u16>, dry_run: bool) -> Result<(), ManagerError> {
+    let current = read_u16("Timeout");
+    let Some(seconds) = seconds else {
+        match current {
+            Some(timeout) => println!("Firmware boot menu waits {}", format_timeout(timeout)),
+            None => println!("Firmware boot menu timeout is not set"),
+        }
+        return Ok(());
+    };
+    if current == Some(seconds) {
+        println!(
+            "Firmware boot menu already waits {}",
+            format_timeout(seconds)
+        );
+    } else if dry_run {
+        println!(
+            "Would set firmware boot menu timeout to {}",
+            format_timeout(seconds)
+        );
+    } else {
+        efivar::system().write(
+            &Variable::new("Timeout"),
+            variable_flags(),
+            &seconds.to_le_bytes(),
+        )?;
+        println!("Firmware boot menu now waits {}", format_timeout(seconds));
+    }
+    Ok(())
+}
+
+/// request or cancel booting into the firmware setup on the next boot
+pub fn firmware_setup_handler(
+    reboot: bool,
+    cancel: bool,
+    dry_run: bool,
+) -> Result<(), ManagerError> {
+    let supported = read_u64("OsIndicationsSupported").unwrap_or(0);
+    if supported & OS_INDICATIONS_BOOT_TO_FW_UI == 0 {
+        return Err(ManagerError::FirmwareUnsupported(
+            "booting into the firmware setup",
+        ));
+    }
+    let indications = read_u64("OsIndications").unwrap_or(0);
+    let new_indications = if cancel {
+        indications & !OS_INDICATIONS_BOOT_TO_FW_UI
+    } else {
+        indications | OS_INDICATIONS_BOOT_TO_FW_UI
+    };
+    let action = if cancel {
+        "boot normally"
+    } else {
+        "enter the firmware setup"
+    };
+    if dry_run {
+        println!("Would {action} on the next boot");
+        if reboot {
+            println!("Would reboot now");
+        }
+        return Ok(());
+    }
+    if new_indications != indications {
+        efivar::system().write(
+            &Variable::new("OsIndications"),
+            variable_flags(),
+            &new_indications.to_le_bytes(),
+        )?;
+    }
+    println!("The system will {action} on the next boot");
+    if reboot {
+        self::reboot();
+    }
+    Ok(())
+}
@@ -0,0 +1,76 @@
+//! Discovery of installed kernels
+//!
+//! Every installed kernel has a modules directory named by its release inside the kernel modules
+//! directory. Distributions differ in where the matching kernel image is placed:
+//!
+//! * Archlinux keeps it inside the modules directory as `vmlinuz`
+//! * Debian, Fedora and `make install` of kernels built from source place it in the boot
+//!   directory as `vmlinuz-<release>`, sometimes uncompressed as `vmlinux-<release>`
+//!
+//! Modules directories without a kernel image are leftovers of upgrades or uninstalls.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// a place kernel images are searched at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KernelLayout {
+    /// `<kernel_modules_dir>/<release>/vmlinuz`
+    Modules,
+    /// `<boot_dir>/vmlinuz-<release>` or `<boot_dir>/vmlinux-<release>`
+    Boot,
+}
+
+/// layouts searched if none are configured, in order
+pub const DEFAULT_LAYOUTS: [KernelLayout; 2] = [KernelLayout::Modules, KernelLayout::Boot];
+
+pub const DEFAULT_BOOT_DIR: &str = "/boot";
+
+impl KernelLayout {
+    /// paths the image of kernel `release` may have in this layout
+    fn image_candidates(&self, modules_dir: &Path, boot_dir: &Path, release: &str) -> Vec<PathBuf> {
+        match self {
+            KernelLayout::Modules => vec![modules_dir.join(release).join("vmlinuz")],
+            KernelLayout::Boot => vec![
+                boot_dir.join(format!("vmlinuz-{release}")),
+                boot_dir.join(format!("vmlinux-{release}")),
+            ],
+        }
+    }
+}
+
+pub struct KernelDiscovery<'a> {
+    pub modules_dir: &'a Path,
+    pub boot_dir: &'a Path,
+    pub layouts: &'a [KernelLayout],
+}
+
+impl KernelDiscovery<'_> {
+    /// the image of kernel `release`, the first existing candidate of all layouts wins
+    pub fn kernel_image(&self, release: &str) -> Option<PathBuf> {
+        self.layouts
+            .iter()
+            .flat_map(|layout| layout.image_candidates(self.modules_dir, self.boot_dir, release))
+            .find(|image| image.is_file())
+    }
+
+    /// releases of all kernels that have a modules directory and a kernel image
+    pub fn installed_releases(&self) -> Vec<String> {
+        let mut releases: Vec<String> = fs::read_dir(self.modules_dir)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .filter(|entry| entry.path().is_dir())
+                    .filter_map(|entry| entry.file_name().into_string().ok())
+                    .filter(|release| self.kernel_image(release).is_some())
+                    .collect()
+            })
+            .unwrap_or_default();
+        releases.sort();
+        releases
+    }
+}
@@ -0,0 +1,180 @@
+//! Kernel release parsing and ordering
+//!
+//! Kernel releases are named very differently across kernel flavours and distributions, e.g.
+//! `6.9.1-arch1-1`, `6.9.1-zen1-1-zen`, `6.10-1`, `6.10.0-rc3-1-mainline` or
+//! `6.8.9-300.fc40.x86_64`. A release is split into the upstream version, an optional release
+//! candidate number and the remaining numbers of the distribution build, which are compared in
+//! that order.
+use std::{cmp::Ordering, fmt::Display};
+
+use regex::Regex;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelVersion {
+    /// name of the kernel modules directory, as reported by `uname -r`
+    pub full_name: String,
+    /// major, minor and patch level, missing components are 0
+    upstream: [u64; 3],
+    /// release candidate number, release candidates sort before the final release
+    rc: Option<u64>,
+    /// all numbers following the upstream version, e.g. `[1, 1]` for `-arch1-1`
+    build: Vec<u64>,
+    /// false if the name does not start with a version, those sort before all others
+    parsed: bool,
+}
+
+impl KernelVersion {
+    pub fn parse(full_name: &str) -> KernelVersion {
+        let upstream_regex = Regex::new(r"^(\d+)\.(\d+)(?:\.(\d+))?").unwrap();
+        let rc_regex = Regex::new(r"^[-.~]?rc(\d+)").unwrap();
+        let number_regex = Regex::new(r"\d+").unwrap();
+
+        let number =
+            |m: Option<regex::Match>| m.and_then(|m| m.as_str().parse::<u64>().ok()).unwrap_or(0);
+
+        let Some(upstream) = upstream_regex.captures(full_name) else {
+            return KernelVersion {
+                full_name: full_name.to_string(),
+                upstream: [0, 0, 0],
+                rc: None,
+                build: Vec::new(),
+                parsed: false,
+            };
+        };
+        let mut rest = &full_name[upstream.get(0).unwrap().end()..];
+        let rc = rc_regex.captures(rest).map(|rc| {
+            rest = &rest[rc.get(0).unwrap().end()..];
+            number(rc.get(1))
+        });
+        let build = number_regex
+            .find_iter(rest)
+            .filter_map(|n| n.as_str().parse::<u64>().ok())
+            .collect();
+        KernelVersion {
+            full_name: full_name.to_string(),
+            upstream: [
+                number(upstream.get(1)),
+                number(upstream.get(2)),
+                number(upstream.get(3)),
+            ],
+            rc,
+            build,
+            parsed: true,
+        }
+    }
+}
+
+/// normalized version, `6.10.0-rc3-1-mainline` is shown as `6.10.0-rc3 (1)`
+impl Display for KernelVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.parsed {
+            return f.write_str("unknown");
+        }
+        let [major, minor, patch] = self.upstream;
+        write!(f, "{major}.{minor}.{patch}")?;
+        if let Some(rc) = self.rc {
+            write!(f, "-rc{rc}")?;
+        }
+        if !self.build.is_empty() {
+            let build = self
+                .build
+                .iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<_>>()
+                .join(".");
+            write!(f, " ({build})")?;
+        }
+        Ok(())
+    }
+}
+
+impl Ord for KernelVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.parsed
+            .cmp(&other.parsed)
+            .then_with(|| self.upstream.cmp(&other.upstream))
+            .then_with(|| match (self.rc, other.rc) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            })
+            .then_with(|| self.build.cmp(&other.build))
+            .then_with(|| self.full_name.cmp(&other.full_name))
+    }
+}
+
+impl PartialOrd for KernelVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod kernel_version_tests {
+    use super::KernelVersion;
+
+    #[test]
+    fn kernel_version_order_test() {
+        // every entry has to be newer than the one before
+        let ordered = [
+            [
+                "6.9.1-arch1-1",
+                "6.9.1-arch1-2",
+                "6.9.1-arch2-1",
+                "6.9.2-arch1-1",
+                "6.9.10-arch1-1",
+                "6.10.0-rc1-1",
+                "6.10.0-rc3-1",
+                "6.10.0-rc10-1",
+                "6.10.0-arch1-1",
+            ]
+            .as_slice(),
+            &[
+                "6.6.9-1-lts",
+                "6.6.30-1-lts",
+                "6.6.30-2-lts",
+                "6.12.1-1-lts",
+            ],
+            &[
+                "6.9.1-zen1-1-zen",
+                "6.9.1-zen2-1-zen",
+                "6.9.3-zen1-1-zen",
+                "6.10.1-zen1-1-zen",
+            ],
+            &["6.9.1.zen1", "6.9.1.zen2", "6.10.1.zen1"],
+            &["6.9-1", "6.9.1-1", "6.10-1", "6.10-2", "6.10.1-1"],
+            &["6.10rc2-1", "6.10rc7-1", "6.10-1"],
+            &["6.1.0-17-amd64", "6.1.0-18-amd64", "6.5.0-0.deb12.4-amd64"],
+            &[
+                "6.8.9-300.fc40.x86_64",
+                "6.8.10-300.fc40.x86_64",
+                "6.8.10-301.fc40.x86_64",
+            ],
+            &["custom", "5.4.0", "6.0.0-custom"],
+        ];
+        for group in ordered {
+            for pair in group.windows(2) {
+                let older = KernelVersion::parse(pair[0]);
+                let newer = KernelVersion::parse(pair[1]);
+                assert!(older < newer, "{} < {}", pair[0], pair[1]);
+            }
+        }
+    }
+
+    #[test]
+    fn kernel_version_parse_test() {
+        let cases = [
+            ("6.9.1-arch1-1", "6.9.1 (1.1)"),
+            ("6.10-1", "6.10.0 (1)"),
+            ("6.10.0-rc3-1-mainline", "6.10.0-rc3 (1)"),
+            ("6.9.1.zen1", "6.9.1 (1)"),
+            ("6.6.30-1-lts", "6.6.30 (1)"),
+            ("6.9.1", "6.9.1"),
+            ("custom", "unknown"),
+        ];
+        for (full_name, normalized) in cases {
+            assert_eq!(KernelVersion::parse(full_name).to_string(), normalized);
+        }
+    }
+}
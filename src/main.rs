@@ -2,27 +2,56 @@
 //!
 //! A tool to create EFI binaries for Archlinux kernels for direct boot without a bootloader.
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     fmt::Display,
     fs::{self, File},
-    io::{self, Read, Write},
+    io::{self, BufRead, BufReader, Read, Write},
     path::{Path, PathBuf},
-    process::Command,
+    process::{Command, ExitCode, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    thread,
 };
 
 use clap::Parser;
-use config::Config;
-use efivar::boot::{BootEntry, BootEntryAttributes, EFIHardDrive, FilePath, FilePathList};
+use config::{Config, ConfigError};
+use efivar::{
+    boot::{BootEntry, BootEntryAttributes, EFIHardDrive, FilePath, FilePathList},
+    efi::{VarFlags, Variable},
+};
 use gpt::{partition::Partition, partition_types};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    boot_entries::DeclaredBootEntry,
+    boot_order::BootOrderPolicy,
+    error::ManagerError,
+    kernel_discovery::{KernelDiscovery, KernelLayout},
+    kernel_version::KernelVersion,
+    module_cleanup::KeepReason,
+};
+
+mod boot_assessment;
+mod boot_entries;
+mod boot_order;
+mod destination;
+mod efi_image;
+mod error;
+mod firmware;
+mod kernel_discovery;
+mod kernel_version;
+mod module_cleanup;
+mod nvram;
+
 #[derive(Parser, Debug)]
 #[command(author, about, version)]
 #[command(
     help_template = "Author: {author} \nVersion: {version} \n{about-section} \n{usage-heading} {usage}\n\n{all-args} {tab}"
 )]
 struct DracutCmdArgs {
+    /// only print what would be done without changing anything
+    #[arg(long, global = true)]
+    dry_run: bool,
     #[command(subcommand)]
     command: DracutBuilderCommands,
 }
@@ -30,57 +59,394 @@ struct DracutCmdArgs {
 #[derive(Debug, Clone, Parser)]
 enum DracutBuilderCommands {
     /// build efi binaries for all configured kernels
-    Build,
+    Build {
+        /// only build the efi binary of this kernel from the build mappings
+        #[arg(long)]
+        kernel: Option<String>,
+        /// build this installed kernel version instead of the newest one
+        #[arg(long)]
+        kver: Option<String>,
+        /// stream dracut output live instead of only writing it to the log file
+        #[arg(short, long)]
+        verbose: bool,
+    },
     /// clean efi directory from kernels that are not required anymore
     Clean,
     /// List all installed kernels
     List,
     /// scan drives for efi partions and add boot entries for efi executables
-    Bootentries,
-    /// interactive boot order manipulation
-    Bootorder,
+    #[command(args_conflicts_with_subcommands = true)]
+    Bootentries {
+        #[command(subcommand)]
+        action: Option<BootentriesCommands>,
+        /// create, update and reorder the boot entries declared in the settings
+        #[arg(long)]
+        apply: bool,
+        /// apply the changes without asking for confirmation
+        #[arg(short, long, requires = "apply")]
+        yes: bool,
+    },
+    /// boot an entry once on the next boot without changing the boot order
+    RebootInto {
+        /// id, description or image of the entry
+        entry: String,
+        /// reboot right away
+        #[arg(long)]
+        reboot: bool,
+    },
+    /// promote a trialled boot entry after it booted successfully
+    MarkGood,
+    /// show or set how long the firmware boot menu waits before booting
+    Timeout {
+        /// seconds to wait, 65535 waits until an entry is chosen
+        seconds: Option<u16>,
+    },
+    /// back up or restore all firmware boot entries and the boot order
+    Nvram {
+        #[command(subcommand)]
+        command: NvramCommands,
+    },
+    /// boot into the firmware setup on the next boot
+    FirmwareSetup {
+        /// reboot right away
+        #[arg(long)]
+        reboot: bool,
+        /// boot normally again
+        #[arg(long, conflicts_with = "reboot")]
+        cancel: bool,
+    },
+    /// interactive boot order manipulation, or scripted with the options
+    Bootorder {
+        /// move the entry with this id, description or image to the front
+        #[arg(long, group = "change")]
+        first: Option<String>,
+        /// replace the boot order with these comma separated ids, e.g. 0003,0001
+        #[arg(long, group = "change", value_delimiter = ',', value_parser = parse_boot_id)]
+        set: Option<Vec<u16>>,
+        /// remove the entry with this id, description or image from the boot order
+        #[arg(long, group = "change")]
+        remove: Option<String>,
+        /// list all boot entries in boot order as json
+        #[arg(long, conflicts_with = "change")]
+        json: bool,
+    },
+}
+
+/// parse a boot entry id given in hex like efibootmgr prints it, `0003` or `Boot0003`
+fn parse_boot_id(id: &str) -> Result<u16, String> {
+    let hex = id.strip_prefix("Boot").unwrap_or(id);
+    u16::from_str_radix(hex, 16).map_err(|_| format!("`{id}` is not a boot entry id like 0003"))
+}
+
+#[derive(Debug, Clone, Parser)]
+enum NvramCommands {
+    /// save all boot entries and the boot order, as json if the file ends in .json, else as toml
+    Backup { file: PathBuf },
+    /// write the boot entries and the boot order of a backup to the firmware
+    Restore {
+        file: PathBuf,
+        /// restore without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+}
+
+/// boot entry category, application entries are not booted by the firmware boot manager itself
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum BootCategory {
+    Boot,
+    App,
+}
+
+#[derive(Debug, Clone, Parser)]
+enum BootentriesCommands {
+    /// remove boot entries pointing to missing partitions or efi binaries
+    Prune {
+        /// remove the entries without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// rewrite boot entries whose partition was moved or resized
+    Repair {
+        /// rewrite the entries without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// change the description or attributes of a boot entry
+    Edit {
+        /// id of the boot entry, e.g. 0003
+        #[arg(value_parser = parse_boot_id)]
+        id: u16,
+        /// new description of the entry
+        #[arg(long)]
+        description: Option<String>,
+        /// enable or disable the entry
+        #[arg(long)]
+        active: Option<bool>,
+        /// hide the entry from the firmware boot menu
+        #[arg(long)]
+        hidden: Option<bool>,
+        #[arg(long, value_enum)]
+        category: Option<BootCategory>,
+    },
+    /// delete a boot entry and remove it from the boot order
+    Delete {
+        /// id of the boot entry, e.g. 0003
+        #[arg(value_parser = parse_boot_id)]
+        id: u16,
+        /// delete the entry without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct EfiStubBuildConfig {
     kernel_modules_dir: String,
     efi_dir: String,
+    /// directory searched for `vmlinuz-<release>` kernel images, `/boot` if unset
+    boot_dir: Option<String>,
+    /// where kernel images are searched for, both the modules directory and `boot_dir` if unset
+    kernel_layouts: Option<Vec<KernelLayout>>,
+    /// remove efi binaries of uninstalled kernels when the efi partition is too small for a build
+    #[serde(default)]
+    clean_on_low_space: bool,
+    /// kernel command line embedded into the efi binaries, dracut's configuration is used if unset
+    kernel_cmdline: Option<String>,
+    /// directory keeping the dracut output of the last build of every kernel
+    log_dir: Option<String>,
+    /// where the efi binaries are placed inside `efi_dir`
+    #[serde(default)]
+    layout: ImageLayout,
+    /// boot attempts counted by the loader for new images in the bls layout
+    bls_tries: Option<u32>,
+    /// place the efi binaries in a subdirectory of `efi_dir` unique to this machine
+    namespace: Option<Namespace>,
+    /// description template of boot entries created for newly built images, which are only
+    /// registered automatically if it is set
+    boot_entry_description: Option<String>,
+    /// boot newly built images once and only promote them after `mark-good`
+    #[serde(default)]
+    boot_assessment: bool,
+    /// order of the firmware boot entries applied after every build
+    boot_order: Option<BootOrderPolicy>,
+    /// firmware boot entries created and ordered by `bootentries --apply`
+    #[serde(default)]
+    boot_entries: Vec<DeclaredBootEntry>,
 
-    build_mappings: BTreeMap<String, String>,
+    build_mappings: BTreeMap<String, BuildMapping>,
 }
 
-#[derive(Debug, Clone)]
-struct KernelVersion {
-    version: String,
-    full_name: String,
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ImageLayout {
+    /// images are named by the destinations of the build mappings
+    #[default]
+    Flat,
+    /// Boot Loader Specification type #2 layout, images are placed in `EFI/Linux` named by
+    /// machine id and kernel version so they are also discovered by systemd-boot
+    Bls,
+}
+
+/// identifier of the subdirectory keeping the efi binaries of this machine, so multiple
+/// installations can share one efi partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Namespace {
+    MachineId,
+    Hostname,
+}
+
+fn get_hostname() -> String {
+    Command::new("uname")
+        .arg("-n")
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_default()
 }
 
-impl Into<KernelVersion> for &dyn ToString {
-    fn into(self) -> KernelVersion {
-        let version_regex = Regex::new(r"[\d]+\.[\d]+\.[\d]+").unwrap();
-        let subversion_regex = Regex::new(r"-([\d]+)").unwrap();
+impl EfiStubBuildConfig {
+    /// lookup of installed kernels and their images for the configured layouts
+    fn kernel_discovery(&self) -> KernelDiscovery<'_> {
+        KernelDiscovery {
+            modules_dir: Path::new(&self.kernel_modules_dir),
+            boot_dir: Path::new(
+                self.boot_dir
+                    .as_deref()
+                    .unwrap_or(kernel_discovery::DEFAULT_BOOT_DIR),
+            ),
+            layouts: self
+                .kernel_layouts
+                .as_deref()
+                .unwrap_or(&kernel_discovery::DEFAULT_LAYOUTS),
+        }
+    }
+
+    /// directory the efi binaries of this machine are placed in
+    ///
+    /// The bls layout already names images by machine id, so it is never namespaced.
+    fn output_dir(&self) -> PathBuf {
+        let efi_dir = Path::new(&self.efi_dir);
+        match (self.layout, self.namespace) {
+            (ImageLayout::Flat, Some(Namespace::MachineId)) => efi_dir.join(get_machine_id()),
+            (ImageLayout::Flat, Some(Namespace::Hostname)) => efi_dir.join(get_hostname()),
+            _ => efi_dir.to_path_buf(),
+        }
+    }
+
+    /// fail if the namespace id of this machine cannot be determined
+    ///
+    /// An empty id would place the images directly in the shared `efi_dir`, where `clean` could
+    /// remove the images of other installations.
+    fn check_namespace(&self) -> Result<(), ConfigError> {
+        let (id, source) = match (self.layout, self.namespace) {
+            (ImageLayout::Flat, Some(Namespace::MachineId)) => {
+                (get_machine_id(), "/etc/machine-id")
+            }
+            (ImageLayout::Flat, Some(Namespace::Hostname)) => (get_hostname(), "the hostname"),
+            _ => return Ok(()),
+        };
+        if id.is_empty() {
+            return Err(ConfigError::Message(format!(
+                "namespace is configured but {source} is empty or unreadable"
+            )));
+        }
+        Ok(())
+    }
+
+    /// the namespace directory if efi binaries are placed in one
+    fn namespace_dir(&self) -> Option<PathBuf> {
+        match (self.layout, self.namespace) {
+            (ImageLayout::Flat, Some(_)) => Some(self.output_dir()),
+            _ => None,
+        }
+    }
+
+    /// destination template of the images of a build mapping for the configured layout
+    fn destination_template<'a>(&self, mapping: &'a BuildMapping) -> &'a str {
+        match self.layout {
+            ImageLayout::Flat => mapping.destination(),
+            ImageLayout::Bls => destination::BLS_TEMPLATE,
+        }
+    }
 
-        let to_parse = self.to_string();
-        let version = version_regex
-            .captures(&to_parse)
-            .and_then(|v| v.get(0))
-            .and_then(|v| Some(v.as_str()))
-            .unwrap_or("0.0.0");
-        let subversion = subversion_regex
-            .captures(&to_parse)
-            .and_then(|v| v.get(1))
-            .and_then(|v| Some(v.as_str()))
-            .unwrap_or("0");
-        KernelVersion {
-            version: format!("{version}.{subversion}"),
-            full_name: to_parse,
+    /// path of a newly built image, adding the initial boot counter in the bls layout
+    fn new_image_path(&self, image: PathBuf) -> PathBuf {
+        match (self.layout, self.bls_tries) {
+            (ImageLayout::Bls, Some(tries)) => destination::with_boot_counter(&image, tries),
+            _ => image,
         }
     }
 }
 
-/// check if the modules directory contains a linux image or is a leftover from upgrades/ùninstalls
-fn is_valid_installation(modules_path: &Path) -> bool {
-    modules_path.join("vmlinuz").exists()
+/// configuration of a single kernel flavour, either just the file name of its efi binary or a
+/// table with additional options
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum BuildMapping {
+    Destination(String),
+    Detailed(BuildMappingOptions),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BuildMappingOptions {
+    destination: String,
+    /// keep building this kernel version instead of the newest one until the pin is removed
+    pin: Option<String>,
+    /// number of previous still installed versions kept as separate versioned efi binaries
+    #[serde(default)]
+    keep_previous: usize,
+    /// value of the `{variant}` placeholder in the destination
+    #[serde(default)]
+    variant: String,
+    /// package name of the kernel, defaults to the mapping name or `linux-<name>`
+    pkgbase: Option<String>,
+    /// regex matching the whole kernel modules directory name, used instead of the pkgbase
+    pattern: Option<String>,
+}
+
+impl BuildMapping {
+    fn destination(&self) -> &str {
+        match self {
+            BuildMapping::Destination(destination) => destination,
+            BuildMapping::Detailed(options) => &options.destination,
+        }
+    }
+
+    fn pin(&self) -> Option<&str> {
+        match self {
+            BuildMapping::Destination(_) => None,
+            BuildMapping::Detailed(options) => options.pin.as_deref(),
+        }
+    }
+
+    fn keep_previous(&self) -> usize {
+        match self {
+            BuildMapping::Destination(_) => 0,
+            BuildMapping::Detailed(options) => options.keep_previous,
+        }
+    }
+
+    fn variant(&self) -> &str {
+        match self {
+            BuildMapping::Destination(_) => "",
+            BuildMapping::Detailed(options) => &options.variant,
+        }
+    }
+
+    /// mappings with a `pkgbase` or `pattern` option never match by the mapping name alone
+    fn has_explicit_match(&self) -> bool {
+        match self {
+            BuildMapping::Destination(_) => false,
+            BuildMapping::Detailed(options) => {
+                options.pkgbase.is_some() || options.pattern.is_some()
+            }
+        }
+    }
+
+    fn matches_kernel(&self, kernel_ident: &str, folder_name: &str, pkgbase: Option<&str>) -> bool {
+        let options = match self {
+            BuildMapping::Detailed(options) if self.has_explicit_match() => options,
+            _ => {
+                return pkgbase.is_some_and(|pkgbase| {
+                    pkgbase == kernel_ident
+                        || pkgbase == format!("linux-{kernel_ident}")
+                        || (pkgbase == "linux" && kernel_ident == "arch")
+                })
+            }
+        };
+        if let Some(expected) = &options.pkgbase {
+            if pkgbase == Some(expected.as_str()) {
+                return true;
+            }
+        }
+        if let Some(pattern) = &options.pattern {
+            match Regex::new(&format!("^(?:{pattern})$")) {
+                Ok(regex) => return regex.is_match(folder_name),
+                Err(err) => eprintln!("Invalid kernel pattern `{pattern}`: {err}"),
+            }
+        }
+        false
+    }
+
+    fn template_values<'a>(
+        &'a self,
+        flavour: &'a str,
+        machine_id: &'a str,
+    ) -> destination::TemplateValues<'a> {
+        destination::TemplateValues {
+            flavour,
+            machine_id,
+            variant: self.variant(),
+        }
+    }
+}
+
+/// read the machine id of the running system, used for the `{machine_id}` placeholder
+fn get_machine_id() -> String {
+    fs::read_to_string("/etc/machine-id")
+        .map(|id| id.trim().to_string())
+        .unwrap_or_default()
 }
 
 fn get_current_running_kernel() -> String {
@@ -92,6 +458,45 @@ fn get_current_running_kernel() -> String {
     current_running_kernel
 }
 
+/// read the name of the package a kernel was installed from, Archlinux kernels record it in the
+/// modules directory
+fn read_pkgbase(modules_path: &Path) -> Option<String> {
+    fs::read_to_string(modules_path.join("pkgbase"))
+        .ok()
+        .map(|pkgbase| pkgbase.trim().to_string())
+        .filter(|pkgbase| !pkgbase.is_empty())
+}
+
+/// find the build mappings a kernel modules directory belongs to
+///
+/// Mappings match by their `pkgbase` or `pattern` option, otherwise by a pkgbase equal to the
+/// mapping name or `linux-<name>`, the `arch` mapping matches the `linux` package. Only kernels
+/// without a pkgbase file fall back to searching the mapping names in the directory name, a
+/// `linux-lts-zfs` kernel is therefore not claimed by `lts`.
+fn match_kernel_directory<'a>(
+    build_mappings: &'a BTreeMap<String, BuildMapping>,
+    folder_name: &str,
+    pkgbase: Option<&str>,
+) -> Vec<&'a String> {
+    let matching: Vec<&String> = build_mappings
+        .iter()
+        .filter(|(kernel_ident, mapping)| {
+            mapping.matches_kernel(kernel_ident, folder_name, pkgbase)
+        })
+        .map(|(kernel_ident, _)| kernel_ident)
+        .collect();
+    if !matching.is_empty() || pkgbase.is_some() {
+        return matching;
+    }
+    build_mappings
+        .iter()
+        .filter(|(kernel_ident, mapping)| {
+            !mapping.has_explicit_match() && folder_name.contains(kernel_ident.as_str())
+        })
+        .map(|(kernel_ident, _)| kernel_ident)
+        .collect()
+}
+
 fn list_all_recognized_kernel_module_directories(
     settings: &EfiStubBuildConfig,
 ) -> BTreeMap<&String, Vec<KernelVersion>> {
@@ -100,32 +505,93 @@ fn list_all_recognized_kernel_module_directories(
         BTreeMap::from_iter(settings.build_mappings.keys().map(|v| (v, Vec::new())));
 
     // cluster kernels by version
-    for entry in fs::read_dir(settings.kernel_modules_dir.clone()).unwrap() {
-        entry.ok().and_then(|entry| {
-            if is_valid_installation(&entry.path()) {
-                let kernel_folder = entry.file_name();
-                for kernel_ident in settings.build_mappings.keys() {
-                    kernel_folder
-                        .clone()
-                        .into_string()
-                        .ok()
-                        .and_then(|kernel_folder_name| {
-                            if kernel_folder_name.contains(kernel_ident) {
-                                found_kernel_modules
-                                    .get_mut(kernel_ident)
-                                    .unwrap()
-                                    .push((&kernel_folder_name as &dyn ToString).into());
-                            }
-                            Some(())
-                        });
-                }
-            }
-            Some(())
-        });
+    for kernel_folder_name in settings.kernel_discovery().installed_releases() {
+        let pkgbase =
+            read_pkgbase(&Path::new(&settings.kernel_modules_dir).join(&kernel_folder_name));
+        let matching = match_kernel_directory(
+            &settings.build_mappings,
+            &kernel_folder_name,
+            pkgbase.as_deref(),
+        );
+        if matching.len() > 1 {
+            eprintln!(
+                "Kernel modules directory {kernel_folder_name} is matched by multiple build mappings: {}",
+                matching
+                    .iter()
+                    .map(|k| k.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        for kernel_ident in matching {
+            found_kernel_modules
+                .get_mut(kernel_ident)
+                .unwrap()
+                .push(KernelVersion::parse(&kernel_folder_name));
+        }
     }
     found_kernel_modules
 }
 
+#[cfg(test)]
+mod kernel_matching_tests {
+    use std::collections::BTreeMap;
+
+    use crate::{match_kernel_directory, BuildMapping, BuildMappingOptions};
+
+    #[test]
+    fn match_kernel_directory_test() {
+        let mut mappings = BTreeMap::new();
+        for kernel in ["arch", "lts", "zen"] {
+            mappings.insert(
+                kernel.to_string(),
+                BuildMapping::Destination(format!("{kernel}.efi")),
+            );
+        }
+        mappings.insert(
+            "custom".to_string(),
+            BuildMapping::Detailed(BuildMappingOptions {
+                destination: "custom.efi".to_string(),
+                pin: None,
+                keep_previous: 0,
+                variant: String::new(),
+                pkgbase: None,
+                pattern: Some(r"[\d.]+-custom".to_string()),
+            }),
+        );
+
+        assert_eq!(
+            match_kernel_directory(&mappings, "6.9.1-arch1-1-zen", Some("linux-zen")),
+            vec!["zen"]
+        );
+        assert_eq!(
+            match_kernel_directory(&mappings, "6.9.1-arch1-1", Some("linux")),
+            vec!["arch"]
+        );
+        assert_eq!(
+            match_kernel_directory(&mappings, "6.6.30-1-lts", Some("linux-lts")),
+            vec!["lts"]
+        );
+        assert_eq!(
+            match_kernel_directory(&mappings, "6.9.1-custom", None),
+            vec!["custom"]
+        );
+        // kernels with an unknown pkgbase are not matched by name
+        assert!(
+            match_kernel_directory(&mappings, "6.6.30-1-lts-zfs", Some("linux-lts-zfs")).is_empty()
+        );
+        assert_eq!(
+            match_kernel_directory(&mappings, "6.6.30-1-lts", None),
+            vec!["lts"]
+        );
+        // patterns are anchored
+        assert!(
+            match_kernel_directory(&mappings, "6.9.1-custom-build", Some("linux-custom"))
+                .is_empty()
+        );
+    }
+}
+
 fn get_newest_installed_kernels(settings: &EfiStubBuildConfig) -> BTreeMap<&String, String> {
     //accumulator for kernels modules directories to find the newest fill with empty vectors
     let found_kernel_modules = list_all_recognized_kernel_module_directories(settings);
@@ -134,165 +600,888 @@ fn get_newest_installed_kernels(settings: &EfiStubBuildConfig) -> BTreeMap<&Stri
     //find the newest kernel for each release type
     let newest_kernels = found_kernel_modules
         .into_iter()
-        .filter_map(|(k, v)| {
-            if v.len() > 1 {
-                let mut newest = v.first().unwrap().clone();
-                for kernel in v {
-                    if version_operators::Version::from_str(&newest.version)
-                        < version_operators::Version::from_str(&kernel.version)
-                    {
-                        newest = kernel.clone();
-                    }
-                }
-                Some((k, newest.full_name.clone()))
-            } else if v.len() == 1 {
-                Some((k, v.first().unwrap().full_name.clone()))
-            } else {
-                None
-            }
-        })
+        .filter_map(|(k, v)| v.into_iter().max().map(|newest| (k, newest.full_name)))
         .collect::<BTreeMap<&String, String>>();
 
     //println!("{newest_kernels:?}");
     newest_kernels
 }
 
-fn build_efi_binaries(settings: &EfiStubBuildConfig) {
-    for kernel in get_newest_installed_kernels(&settings) {
-        let version = kernel.1;
-        let destination = Path::new(&settings.efi_dir).join(
-            settings
-                .build_mappings
-                .get(kernel.0)
-                .expect("Error getting binary destination from config!"),
+/// version of the primary image of every kernel, the pinned or the newest installed version
+///
+/// Pinned kernels keep their image even if the pinned version is not installed anymore.
+fn get_primary_kernels(settings: &EfiStubBuildConfig) -> BTreeMap<&String, String> {
+    let mut primary_kernels = get_newest_installed_kernels(settings);
+    for (configured_kernel, mapping) in settings.build_mappings.iter() {
+        if let Some(pin) = mapping.pin() {
+            primary_kernels.insert(configured_kernel, pin.to_string());
+        }
+    }
+    primary_kernels
+}
+
+/// images of the primary and retained previous kernel versions relative to the root of the efi
+/// partition at `mount_dir`, both newest first
+fn get_ranked_images(settings: &EfiStubBuildConfig, mount_dir: &Path) -> boot_order::RankedImages {
+    let machine_id = get_machine_id();
+    let output_dir = settings.output_dir();
+    let primary_kernels = get_primary_kernels(settings);
+    let retained_kernels = get_retained_kernels(settings, &primary_kernels);
+    let mut primary = Vec::new();
+    let mut previous = Vec::new();
+    for (kernel, mapping) in settings.build_mappings.iter() {
+        let values = mapping.template_values(kernel, &machine_id);
+        let template = settings.destination_template(mapping);
+        if let Some(version) = primary_kernels.get(kernel) {
+            primary.push((
+                KernelVersion::parse(version),
+                output_dir.join(destination::render(template, &values, version)),
+            ));
+        }
+        let retained_template = destination::retained_template(template);
+        for version in retained_kernels.get(kernel).into_iter().flatten() {
+            previous.push((
+                KernelVersion::parse(version),
+                output_dir.join(destination::render(&retained_template, &values, version)),
+            ));
+        }
+    }
+    let rank = |mut images: Vec<(KernelVersion, PathBuf)>| {
+        images.sort_by(|a, b| b.0.cmp(&a.0));
+        images
+            .into_iter()
+            .filter_map(|(_, image)| Some(image.strip_prefix(mount_dir).ok()?.to_path_buf()))
+            .collect()
+    };
+    boot_order::RankedImages {
+        primary: rank(primary),
+        previous: rank(previous),
+    }
+}
+
+/// previous installed versions of every kernel that are kept as versioned efi binaries next to
+/// the primary ones, newest first
+fn get_retained_kernels<'a>(
+    settings: &'a EfiStubBuildConfig,
+    primary_kernels: &BTreeMap<&'a String, String>,
+) -> BTreeMap<&'a String, Vec<String>> {
+    list_all_recognized_kernel_module_directories(settings)
+        .into_iter()
+        .filter_map(|(kernel, mut versions)| {
+            let keep_previous = settings.build_mappings.get(kernel)?.keep_previous();
+            let primary = primary_kernels.get(kernel)?;
+            versions.sort_by(|a, b| b.cmp(a));
+            let retained = versions
+                .into_iter()
+                .map(|v| v.full_name)
+                .filter(|version| version != primary)
+                .take(keep_previous)
+                .collect();
+            Some((kernel, retained))
+        })
+        .collect()
+}
+
+/// safety margin added to every size estimate, dracut output varies slightly between builds
+const BUILD_SIZE_MARGIN_PERCENT: u64 = 5;
+
+fn format_size(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+/// query the free space of the filesystem containing `path` in bytes
+fn get_available_space(path: &Path) -> Option<u64> {
+    let df = Command::new("df")
+        .args(["--output=avail", "-B1"])
+        .arg(path)
+        .output()
+        .ok()?;
+    if !df.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&df.stdout)
+        .lines()
+        .nth(1)
+        .and_then(|avail| avail.trim().parse::<u64>().ok())
+}
+
+/// check if `name` is an initramfs as placed in the boot directory, `initramfs-*.img` on
+/// Archlinux and Fedora or `initrd.img-*` on Debian
+fn is_boot_initramfs(name: &str) -> bool {
+    (name.starts_with("initramfs-") && name.ends_with(".img")) || name.starts_with("initrd.img-")
+}
+
+/// size of the largest initramfs in the boot directory, used for kernels without a previous
+/// image
+fn largest_boot_initramfs(boot_dir: &Path) -> Option<u64> {
+    fs::read_dir(boot_dir)
+        .ok()?
+        .flatten()
+        .filter(|entry| is_boot_initramfs(&entry.file_name().to_string_lossy()))
+        .filter_map(|entry| entry.metadata().ok().map(|m| m.len()))
+        .max()
+}
+
+/// additional bytes occupied by an image of `kernel_size` and `initramfs_size` replacing a
+/// previous image of `previous_size`
+fn required_space(kernel_size: u64, initramfs_size: u64, previous_size: u64) -> u64 {
+    let estimate = (kernel_size + initramfs_size) * (100 + BUILD_SIZE_MARGIN_PERCENT) / 100;
+    estimate.saturating_sub(previous_size)
+}
+
+/// estimate how many additional bytes building the efi binary at `destination` will occupy
+///
+/// The new image is expected to be the size of the new kernel plus an initramfs as large as the
+/// one embedded in the previous image, or as the largest initramfs in the boot directory if there
+/// is no previous image. Space occupied by the previous image is freed when it is overwritten and
+/// therefore subtracted again.
+fn estimate_required_space(
+    settings: &EfiStubBuildConfig,
+    version: &str,
+    destination: &Path,
+) -> u64 {
+    let discovery = settings.kernel_discovery();
+    let kernel_size = discovery
+        .kernel_image(version)
+        .and_then(|image| fs::metadata(image).ok())
+        .map(|m| m.len())
+        .unwrap_or(0);
+    let previous_size = fs::metadata(destination).map(|m| m.len()).unwrap_or(0);
+    let initramfs_size = efi_image::embedded_initramfs_size(destination)
+        .or_else(|| largest_boot_initramfs(discovery.boot_dir))
+        .unwrap_or(0);
+    required_space(kernel_size, initramfs_size, previous_size)
+}
+
+#[cfg(test)]
+mod space_estimate_tests {
+    use crate::{is_boot_initramfs, required_space};
+
+    #[test]
+    fn boot_initramfs_test() {
+        assert!(is_boot_initramfs("initramfs-linux.img"));
+        assert!(is_boot_initramfs("initramfs-6.9.1-200.fc40.x86_64.img"));
+        assert!(is_boot_initramfs("initrd.img-6.1.0-21-amd64"));
+        assert!(!is_boot_initramfs("vmlinuz-6.1.0-21-amd64"));
+        assert!(!is_boot_initramfs("initramfs-linux.img.bak"));
+    }
+
+    #[test]
+    fn required_space_test() {
+        // new flavour, 12 MiB kernel and 30 MiB initramfs plus margin
+        assert_eq!(required_space(12 << 20, 30 << 20, 0), 46_242_201);
+        // a slightly larger kernel replacing an image of the same initramfs
+        assert_eq!(required_space(13 << 20, 30 << 20, 42 << 20), 3_303_014);
+        assert_eq!(required_space(10 << 20, 30 << 20, 50 << 20), 0);
+    }
+}
+
+/// check that all planned efi binaries fit into the efi partition, optionally making room first
+fn check_efi_space_budget(
+    settings: &EfiStubBuildConfig,
+    jobs: &[BuildJob],
+    dry_run: bool,
+) -> Result<(), ManagerError> {
+    let required: u64 = jobs
+        .iter()
+        .map(|job| estimate_required_space(settings, &job.version, &job.destination))
+        .sum();
+    let mut available = match get_available_space(Path::new(&settings.efi_dir)) {
+        Some(available) => available,
+        None => {
+            eprintln!(
+                "Could not determine free space in {}, skipping size check.",
+                settings.efi_dir
+            );
+            return Ok(());
+        }
+    };
+    if dry_run {
+        println!(
+            "Building requires about {} in {}, {} are available.",
+            format_size(required),
+            settings.efi_dir,
+            format_size(available)
+        );
+    }
+    if required > available && settings.clean_on_low_space {
+        println!(
+            "Not enough free space in {}, removing stale efi binaries first.",
+            settings.efi_dir
+        );
+        let (_, freed_bytes) = clean_stale_efi_binaries(settings, dry_run)?;
+        // nothing is removed in a dry run, count the space the removal would free instead
+        available = if dry_run {
+            available + freed_bytes
+        } else {
+            get_available_space(Path::new(&settings.efi_dir)).unwrap_or(available)
+        };
+    }
+    if required > available && dry_run {
+        println!("The build would be aborted because of insufficient space.");
+    } else if required > available {
+        return Err(ManagerError::InsufficientSpace {
+            required,
+            available,
+        });
+    }
+    Ok(())
+}
+
+/// number of dracut error lines printed when a build fails
+const DRACUT_ERROR_TAIL_LINES: usize = 10;
+
+#[cfg(debug_assertions)]
+const DEFAULT_LOG_DIR: &str = "logs";
+
+#[cfg(not(debug_assertions))]
+const DEFAULT_LOG_DIR: &str = "/var/log/dracut-efi-manager";
+
+/// create the log file keeping the output of the last dracut run for a kernel
+///
+/// Builds continue without a log if it cannot be created.
+fn dracut_log_file(settings: &EfiStubBuildConfig, kernel: &str) -> Option<(PathBuf, File)> {
+    let log_dir = Path::new(settings.log_dir.as_deref().unwrap_or(DEFAULT_LOG_DIR));
+    if let Err(err) = fs::create_dir_all(log_dir) {
+        eprintln!(
+            "Could not create log directory {}: {err}",
+            log_dir.display()
         );
+        return None;
+    }
+    let path = log_dir.join(format!("{kernel}.log"));
+    match File::create(&path) {
+        Ok(file) => Some((path, file)),
+        Err(err) => {
+            eprintln!("Could not create log file {}: {err}", path.display());
+            None
+        }
+    }
+}
+
+/// run dracut writing all output into `log`, optionally echoing it live to the terminal
+///
+/// Returns the exit status and the lines dracut wrote to stderr.
+fn run_dracut(
+    dracut_command: &mut Command,
+    log: Option<File>,
+    verbose: bool,
+) -> io::Result<(ExitStatus, Vec<String>)> {
+    let log = Arc::new(Mutex::new(log));
+    let mut child = dracut_command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let stdout = child.stdout.take().unwrap();
+    let stdout_log = log.clone();
+    let stdout_reader = thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if verbose {
+                println!("{line}");
+            }
+            if let Some(log) = stdout_log.lock().unwrap().as_mut() {
+                let _ = writeln!(log, "{line}");
+            }
+        }
+    });
+
+    let mut stderr_lines = Vec::new();
+    for line in BufReader::new(child.stderr.take().unwrap())
+        .lines()
+        .map_while(Result::ok)
+    {
+        if verbose {
+            eprintln!("{line}");
+        }
+        if let Some(log) = log.lock().unwrap().as_mut() {
+            let _ = writeln!(log, "{line}");
+        }
+        stderr_lines.push(line);
+    }
+    let _ = stdout_reader.join();
+    Ok((child.wait()?, stderr_lines))
+}
+
+/// render a command the way it would be typed into a shell
+fn format_command(command: &Command) -> String {
+    std::iter::once(command.get_program())
+        .chain(command.get_args())
+        .map(|arg| arg.to_string_lossy().to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// decide which kernel version to build for every flavour
+///
+/// By default the newest installed version of every flavour is built, unless the flavour is pinned
+/// to a version in the config. `kernel` restricts the build to a single flavour and `kver` to a
+/// specific installed version.
+fn select_kernels_to_build<'a>(
+    settings: &'a EfiStubBuildConfig,
+    kernel: Option<&str>,
+    kver: Option<&str>,
+) -> Result<BTreeMap<&'a String, String>, ManagerError> {
+    if let Some(kernel) = kernel {
+        if !settings.build_mappings.contains_key(kernel) {
+            return Err(ManagerError::KernelNotFound(kernel.to_string()));
+        }
+    }
+    let installed_kernels = list_all_recognized_kernel_module_directories(settings);
+    let is_installed = |flavour: &String, version: &str| {
+        installed_kernels
+            .get(flavour)
+            .is_some_and(|versions| versions.iter().any(|v| v.full_name == version))
+    };
+
+    let mut selected = BTreeMap::new();
+    for (flavour, newest) in get_newest_installed_kernels(settings) {
+        if kernel.is_some_and(|kernel| kernel != flavour) {
+            continue;
+        }
+        if let Some(kver) = kver {
+            if is_installed(flavour, kver) {
+                selected.insert(flavour, kver.to_string());
+            }
+            continue;
+        }
+        match settings.build_mappings.get(flavour).and_then(|m| m.pin()) {
+            Some(pin) if is_installed(flavour, pin) => {
+                selected.insert(flavour, pin.to_string());
+            }
+            Some(pin) => {
+                eprintln!(
+                    "Kernel {flavour} is pinned to {pin} which is not installed, skipping it."
+                );
+            }
+            None => {
+                selected.insert(flavour, newest);
+            }
+        }
+    }
+    if selected.is_empty() {
+        if let Some(missing) = kver.or(kernel) {
+            return Err(ManagerError::KernelNotFound(missing.to_string()));
+        }
+    }
+    Ok(selected)
+}
+
+/// a single efi binary to build
+#[derive(Clone)]
+struct BuildJob {
+    /// name used for the dracut log file
+    name: String,
+    /// key of the build mapping
+    kernel: String,
+    version: String,
+    destination: PathBuf,
+    /// image of a retained previous version instead of the primary image of the mapping
+    retained: bool,
+}
+
+/// plan the efi binaries to build, the selected kernels are always rebuilt while images of
+/// retained previous versions are only built if they do not exist yet
+fn plan_build_jobs(
+    settings: &EfiStubBuildConfig,
+    kernel: Option<&str>,
+    kver: Option<&str>,
+) -> Result<Vec<BuildJob>, ManagerError> {
+    let kernels = select_kernels_to_build(settings, kernel, kver)?;
+    let machine_id = get_machine_id();
+    let output_dir = settings.output_dir();
+    let mut jobs = Vec::new();
+    for (kernel, version) in kernels.iter() {
+        let mapping = settings
+            .build_mappings
+            .get(*kernel)
+            .expect("Error getting binary destination from config!");
+        let values = mapping.template_values(kernel, &machine_id);
+        let image = output_dir.join(destination::render(
+            settings.destination_template(mapping),
+            &values,
+            version,
+        ));
+        jobs.push(BuildJob {
+            name: kernel.to_string(),
+            kernel: kernel.to_string(),
+            version: version.clone(),
+            destination: settings.new_image_path(image),
+            retained: false,
+        });
+    }
+    // an explicitly requested version replaces the primary image only
+    if kver.is_none() {
+        for (kernel, versions) in get_retained_kernels(settings, &kernels) {
+            let mapping = settings.build_mappings.get(kernel).unwrap();
+            let values = mapping.template_values(kernel, &machine_id);
+            let template = destination::retained_template(settings.destination_template(mapping));
+            for version in versions {
+                let retained_destination =
+                    output_dir.join(destination::render(&template, &values, &version));
+                if destination::boot_counter_variants(&retained_destination).is_empty() {
+                    jobs.push(BuildJob {
+                        name: format!("{kernel}-{version}"),
+                        kernel: kernel.to_string(),
+                        version,
+                        destination: settings.new_image_path(retained_destination),
+                        retained: true,
+                    });
+                }
+            }
+        }
+    }
+    Ok(jobs)
+}
+
+fn build_efi_binaries(
+    settings: &EfiStubBuildConfig,
+    kernel: Option<&str>,
+    kver: Option<&str>,
+    verbose: bool,
+    dry_run: bool,
+) -> Result<(), ManagerError> {
+    let jobs = plan_build_jobs(settings, kernel, kver)?;
+    check_efi_space_budget(settings, &jobs, dry_run)?;
+    let mut failed_kernels = Vec::new();
+    let mut built_jobs = Vec::new();
+    // images that did not exist before, only those can be trialled
+    let mut new_images = Vec::new();
+    for job in jobs {
+        let version = job.version.clone();
+        let destination = job.destination.clone();
+        if destination::boot_counter_variants(&destination).is_empty() {
+            new_images.push(job.clone());
+        }
+        let mut dracut_command = Command::new("dracut");
+        dracut_command.args([
+            "--force",
+            "--uefi",
+            "--uefi-stub",
+            "/usr/lib/systemd/boot/efi/linuxx64.efi.stub",
+            destination.to_str().unwrap(),
+            "--kver",
+            &version,
+        ]);
+        // dracut only looks for images in the modules directory and as /boot/vmlinuz-<release>
+        if let Some(image) = settings.kernel_discovery().kernel_image(&version) {
+            dracut_command.arg("--kernel-image").arg(image);
+        }
+        if let Some(cmdline) = &settings.kernel_cmdline {
+            dracut_command.args(["--kernel-cmdline", cmdline]);
+        }
+        if dry_run {
+            println!(
+                "Would build efi binary for kernel {version} at {}: {}",
+                destination.file_name().unwrap().to_str().unwrap(),
+                format_command(&dracut_command)
+            );
+            built_jobs.push(job);
+            continue;
+        }
         print!(
             "Building efi binary for kernel {version} at {} … ",
             destination.file_name().unwrap().to_str().unwrap()
         );
+        if verbose {
+            println!();
+        }
         let _ = io::stdout().flush();
-        let dracut_build = Command::new("dracut")
-            .args([
-                "--force",
-                "--uefi",
-                "--uefi-stub",
-                "/usr/lib/systemd/boot/efi/linuxx64.efi.stub",
-                destination.to_str().unwrap(),
-                "--kver",
-                &version,
-            ])
-            .output();
+        // destinations in subdirectories like EFI/Linux may not exist yet
+        if let Some(parent) = destination.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        let (log_file, log) = dracut_log_file(settings, &job.name).unzip();
+        let dracut_build = run_dracut(&mut dracut_command, log, verbose);
         match dracut_build {
-            Ok(result) => {
-                if result.status.success() {
-                    println!("✅");
+            Ok((status, stderr)) => {
+                if status.success() {
+                    match efi_image::validate_efi_binary(
+                        &destination,
+                        &version,
+                        settings.kernel_cmdline.as_deref(),
+                    ) {
+                        Ok(()) => {
+                            println!("✅");
+                            // the rebuilt image replaces copies with a different boot counter
+                            for variant in destination::boot_counter_variants(&destination) {
+                                if variant != destination {
+                                    let _ = fs::remove_file(variant);
+                                }
+                            }
+                            built_jobs.push(job);
+                        }
+                        Err(err) => {
+                            println!("❌ ({err})");
+                            failed_kernels.push(version);
+                        }
+                    }
                 } else {
                     println!("❌");
+                    failed_kernels.push(version.clone());
+                    if !verbose {
+                        let tail_start = stderr.len().saturating_sub(DRACUT_ERROR_TAIL_LINES);
+                        for line in &stderr[tail_start..] {
+                            eprintln!("    {line}");
+                        }
+                    }
+                    if let Some(log_file) = &log_file {
+                        eprintln!("Full dracut output written to {}", log_file.display());
+                    }
                 }
             }
-            Err(_err) => {
-                println!("❌");
+            Err(err) => {
+                println!("❌ (could not run dracut: {err})");
+                failed_kernels.push(version);
             }
         }
     }
+    // boot entry updates are best effort, failed builds are reported first
+    let mut post_build_results = vec![boot_entries::register_built_images(
+        settings,
+        &built_jobs,
+        dry_run,
+    )];
+    if let Some(policy) = &settings.boot_order {
+        post_build_results.push(boot_order::apply_policy(settings, policy, dry_run));
+    }
+    // only successfully built images are trialled
+    new_images.retain(|job| !failed_kernels.contains(&job.version));
+    post_build_results.push(boot_assessment::start_trial(settings, &new_images, dry_run));
+    let mut post_build_errors = post_build_results.into_iter().filter_map(Result::err);
+    let first_error = if failed_kernels.is_empty() {
+        post_build_errors.next()
+    } else {
+        Some(ManagerError::BuildFailed(failed_kernels))
+    };
+    for err in post_build_errors {
+        eprintln!("{err}");
+    }
+    match first_error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
 }
 
-fn clean_efi_binaries(settings: &EfiStubBuildConfig) {
-    let mut removed_binarys = 0;
-    let installed_kernels = get_newest_installed_kernels(&settings);
-    for (configured_kernel, destination_name) in settings.build_mappings.iter() {
-        // check if configured kernel is installed
-        if !installed_kernels.contains_key(configured_kernel) {
-            removed_binarys += 1;
-            //if not check if there still is an efi binary present and if so remove it
-            let destination = Path::new(&settings.efi_dir).join(destination_name);
-            if destination.exists() {
-                print!("Removing old efi binary for {configured_kernel} kernel at {destination_name} … ");
-                let _ = io::stdout().flush();
-                let remove_old_binary = Command::new("rm")
-                    .arg(destination.to_str().unwrap())
-                    .output();
-                match remove_old_binary {
-                    Ok(result) => {
-                        if result.status.success() {
-                            println!("✅");
-                        } else {
-                            println!("❌");
-                        }
-                    }
-                    Err(_err) => {
-                        println!("❌");
-                    }
-                }
+/// remove an efi binary reporting progress, returns false if the removal failed
+fn remove_efi_binary(configured_kernel: &str, destination: &Path, dry_run: bool) -> bool {
+    let destination_name = destination.file_name().unwrap().to_string_lossy();
+    if dry_run {
+        println!(
+            "Would remove old efi binary for {configured_kernel} kernel at {destination_name}"
+        );
+        return true;
+    }
+    print!("Removing old efi binary for {configured_kernel} kernel at {destination_name} … ");
+    let _ = io::stdout().flush();
+    let remove_old_binary = Command::new("rm")
+        .arg(destination.to_str().unwrap())
+        .output();
+    match remove_old_binary {
+        Ok(result) => {
+            if result.status.success() {
+                println!("✅");
+                true
+            } else {
+                println!("❌");
+                false
             }
         }
+        Err(_err) => {
+            println!("❌");
+            false
+        }
+    }
+}
+
+/// whether the kernel an image in the bls layout is named after belongs to `configured_kernel`
+///
+/// Other tools like `kernel-install` place images with the same names in `EFI/Linux`, images of
+/// kernels no mapping matches are left to them.
+fn bls_image_kernel_matches(
+    settings: &EfiStubBuildConfig,
+    configured_kernel: &String,
+    values: &destination::TemplateValues,
+    binary: &Path,
+) -> bool {
+    let Some(version) = destination::file_version(destination::BLS_TEMPLATE, values, binary) else {
+        return false;
+    };
+    let pkgbase = read_pkgbase(&Path::new(&settings.kernel_modules_dir).join(&version));
+    match_kernel_directory(&settings.build_mappings, &version, pkgbase.as_deref())
+        .contains(&configured_kernel)
+}
+
+/// efi binaries inside `efi_dir` created from the destination template of a build mapping that
+/// are not required by any mapping, together with the mapping whose template matched them
+///
+/// All mappings share one template in the bls layout, so the required images of every mapping
+/// are collected before deciding which owned files are stale.
+fn find_stale_efi_binaries<'a>(
+    settings: &'a EfiStubBuildConfig,
+    efi_dir: &Path,
+    machine_id: &str,
+    primary_kernels: &BTreeMap<&'a String, String>,
+    retained_kernels: &BTreeMap<&'a String, Vec<String>>,
+) -> BTreeMap<PathBuf, &'a String> {
+    let mut required_binaries = BTreeSet::new();
+    let mut owned_binaries = BTreeMap::new();
+    for (configured_kernel, mapping) in settings.build_mappings.iter() {
+        let values = mapping.template_values(configured_kernel, machine_id);
+        let template = settings.destination_template(mapping);
+        let retained_template = destination::retained_template(template);
+
+        if let Some(version) = primary_kernels.get(configured_kernel) {
+            required_binaries.insert(efi_dir.join(destination::render(template, &values, version)));
+        }
+        for version in retained_kernels
+            .get(configured_kernel)
+            .into_iter()
+            .flatten()
+        {
+            required_binaries.insert(efi_dir.join(destination::render(
+                &retained_template,
+                &values,
+                version,
+            )));
+        }
+
+        for binary in destination::find_owned_files(efi_dir, template, &values)
+            .into_iter()
+            .chain(destination::find_owned_files(
+                efi_dir,
+                &retained_template,
+                &values,
+            ))
+            .filter(|binary| {
+                settings.layout != ImageLayout::Bls
+                    || bls_image_kernel_matches(settings, configured_kernel, &values, binary)
+            })
+        {
+            owned_binaries.entry(binary).or_insert(configured_kernel);
+        }
+    }
+    owned_binaries
+        .into_iter()
+        .filter(|(binary, _)| !required_binaries.contains(&destination::strip_boot_counter(binary)))
+        .collect()
+}
+
+/// remove efi binaries of configured kernels that are no longer installed and versioned efi
+/// binaries exceeding the retention policy, returns the number of binaries that were not required
+/// anymore and the bytes removing them frees
+///
+/// Files are recognised by the destination templates of the build mappings, so images named after
+/// kernel versions that are gone are removed as well.
+fn clean_stale_efi_binaries(
+    settings: &EfiStubBuildConfig,
+    dry_run: bool,
+) -> Result<(usize, u64), ManagerError> {
+    let mut failed_removals = Vec::new();
+    let mut removed_paths = Vec::new();
+    let mut freed_bytes = 0;
+    let machine_id = get_machine_id();
+    let output_dir = settings.output_dir();
+
+    let primary_kernels = get_primary_kernels(settings);
+    let retained_kernels = get_retained_kernels(settings, &primary_kernels);
+    let stale_binaries = find_stale_efi_binaries(
+        settings,
+        &output_dir,
+        &machine_id,
+        &primary_kernels,
+        &retained_kernels,
+    );
+
+    for (binary, configured_kernel) in stale_binaries.iter() {
+        let size = fs::metadata(binary).map(|m| m.len()).unwrap_or(0);
+        if remove_efi_binary(configured_kernel, binary, dry_run) {
+            freed_bytes += size;
+            removed_paths.push(binary.clone());
+        } else {
+            failed_removals.push(binary.clone());
+        }
     }
-    if removed_binarys == 0 {
-        println!("Efi directory is already clean.");
+
+    // entries of removed images would be left dangling in the firmware
+    if let Err(err) = boot_entries::remove_entries_for_images(settings, &removed_paths, dry_run) {
+        eprintln!("Could not remove boot entries of removed efi binaries: {err}");
     }
+
+    if failed_removals.is_empty() {
+        Ok((stale_binaries.len(), freed_bytes))
+    } else {
+        Err(ManagerError::CleanFailed(failed_removals))
+    }
+}
+
+#[cfg(test)]
+mod stale_binary_tests {
+    use std::{collections::BTreeMap, fs};
+
+    use crate::{find_stale_efi_binaries, EfiStubBuildConfig};
+
+    #[test]
+    fn bls_stale_binaries_test() {
+        let settings: EfiStubBuildConfig = toml::from_str(
+            r#"
+            kernel_modules_dir = "/usr/lib/modules"
+            efi_dir = "/efi"
+            layout = "bls"
+
+            [build_mappings]
+            arch = "ArchLinux.efi"
+            lts = "ArchLinuxLts.efi"
+            "#,
+        )
+        .unwrap();
+        let efi_dir = std::env::temp_dir().join(format!("stale_binaries_{}", std::process::id()));
+        let linux_dir = efi_dir.join("EFI/Linux");
+        fs::create_dir_all(&linux_dir).unwrap();
+        for image in [
+            "4b1d-6.9.1-arch1-1.efi",
+            "4b1d-6.6.30-1-lts+2.efi",
+            "4b1d-6.8.9-arch1-1.efi",
+            "ffff-6.9.1-arch1-1.efi",
+            // installed by kernel-install for a kernel without a mapping
+            "4b1d-6.10.0-custom.efi",
+        ] {
+            fs::write(linux_dir.join(image), "").unwrap();
+        }
+        let arch = "arch".to_string();
+        let lts = "lts".to_string();
+        let primary_kernels = BTreeMap::from([
+            (&arch, "6.9.1-arch1-1".to_string()),
+            (&lts, "6.6.30-1-lts".to_string()),
+        ]);
+
+        let stale = find_stale_efi_binaries(
+            &settings,
+            &efi_dir,
+            "4b1d",
+            &primary_kernels,
+            &BTreeMap::new(),
+        );
+        fs::remove_dir_all(&efi_dir).unwrap();
+        assert_eq!(
+            stale.keys().collect::<Vec<_>>(),
+            vec![&linux_dir.join("4b1d-6.8.9-arch1-1.efi")]
+        );
+    }
+}
+
+fn clean_efi_binaries(settings: &EfiStubBuildConfig, dry_run: bool) -> Result<(), ManagerError> {
+    let mut failed_removals = match clean_stale_efi_binaries(settings, dry_run) {
+        Ok((0, _)) => {
+            println!("Efi directory is already clean.");
+            Vec::new()
+        }
+        Ok(_) => Vec::new(),
+        Err(ManagerError::CleanFailed(paths)) => paths,
+        Err(err) => return Err(err),
+    };
     //cleanup old kernel directories
-    for entry in fs::read_dir(settings.kernel_modules_dir.clone()).unwrap() {
-        entry.ok().and_then(|entry| {
-            entry
-                .file_name()
-                .into_string()
-                .ok()
-                .and_then(|kernel_name| {
-                    if kernel_name != get_current_running_kernel()
-                        && !is_valid_installation(&entry.path())
-                    {
-                        print!("Removing old kernel modules directory {kernel_name} … ");
-                        let _ = io::stdout().flush();
-                        let remove_old_kernel_module_dir = Command::new("rm")
-                            .args(["-rf", entry.path().to_str().unwrap()])
-                            .output();
-                        match remove_old_kernel_module_dir {
-                            Ok(result) => {
-                                if result.status.success() {
-                                    println!("✅");
-                                } else {
-                                    println!("❌");
-                                }
-                            }
-                            Err(_err) => {
-                                println!("❌");
-                            }
-                        }
-                    }
-                    Some(())
-                })
-        });
+    let discovery = settings.kernel_discovery();
+    let running_kernel = get_current_running_kernel();
+    let mut freed_bytes = 0;
+    for (release, path) in
+        module_cleanup::module_directories(Path::new(&settings.kernel_modules_dir))
+    {
+        match module_cleanup::keep_reason(&discovery, &path, &release, &running_kernel) {
+            Some(KeepReason::Running | KeepReason::Installed) => continue,
+            Some(reason) => {
+                println!("Keeping kernel modules directory {release}: {reason}");
+                continue;
+            }
+            None => {}
+        }
+        let size = module_cleanup::directory_size(&path);
+        if dry_run {
+            println!(
+                "Would remove old kernel modules directory {release} ({})",
+                format_size(size)
+            );
+            freed_bytes += size;
+            continue;
+        }
+        print!(
+            "Removing old kernel modules directory {release} ({}) … ",
+            format_size(size)
+        );
+        let _ = io::stdout().flush();
+        match fs::remove_dir_all(&path) {
+            Ok(()) => {
+                println!("✅");
+                freed_bytes += size;
+            }
+            Err(err) => {
+                println!("❌ {err}");
+                failed_removals.push(path);
+            }
+        }
+    }
+    if freed_bytes > 0 && dry_run {
+        println!("Would free {} of kernel modules.", format_size(freed_bytes));
+    } else if freed_bytes > 0 {
+        println!("Freed {} of kernel modules.", format_size(freed_bytes));
+    }
+    if failed_removals.is_empty() {
+        Ok(())
+    } else {
+        Err(ManagerError::CleanFailed(failed_removals))
     }
 }
 
-fn boot_entries_handler() {
+/// offer to create boot entries for efi binaries without one
+///
+/// If the efi binaries are namespaced only binaries inside the namespace of this machine are
+/// considered.
+fn boot_entries_handler(
+    settings: Option<&EfiStubBuildConfig>,
+    dry_run: bool,
+) -> Result<(), ManagerError> {
+    let namespace_dir = settings.and_then(|settings| settings.namespace_dir());
     let efi_partitions = get_efi_partitions();
     if efi_partitions.is_empty() {
         println!("No efi partitions found. No boot entries to configure.");
     } else {
         for efi_part in efi_partitions {
-            let efi_binaries = efi_part.get_efi_binaries();
+            let mut efi_binaries = efi_part.get_efi_binaries();
+            if let Some(namespace_dir) = &namespace_dir {
+                let mount_dir = efi_part
+                    .get_partiton_device()
+                    .and_then(|device| get_mount_dir(&device));
+                efi_binaries.retain(|efi_bin| {
+                    mount_dir
+                        .as_ref()
+                        .is_some_and(|mount_dir| mount_dir.join(efi_bin).starts_with(namespace_dir))
+                });
+            }
             let exisiting_boot_entries = efi_part.existing_boot_entries();
             for efi_bin in efi_binaries {
-                if !exisiting_boot_entries.contains_key(&efi_bin) {
+                if !exisiting_boot_entries.contains_key(&efi_bin) && dry_run {
+                    println!(
+                        "Would offer to create a boot entry for efi binary `{:?}`",
+                        efi_bin.as_path()
+                    );
+                } else if !exisiting_boot_entries.contains_key(&efi_bin) {
                     if dialoguer::Confirm::new()
                         .with_prompt(format!(
                             "No boot entry found for efi binary `{:?}`. Do you want to create one?",
                             efi_bin.as_path()
                         ))
-                        .interact()
-                        .unwrap()
+                        .interact()?
                     {
                         let description: String = dialoguer::Input::new()
                             .with_prompt("Give the boot Entry a description:")
-                            .interact()
-                            .unwrap();
-                        add_boot_entry(efi_part.gen_boot_entry(&efi_bin, description), None);
+                            .interact()?;
+                        add_boot_entry(efi_part.gen_boot_entry(&efi_bin, description), None)?;
                     }
                 }
             }
         }
     }
+    Ok(())
 }
 
 #[cfg(debug_assertions)]
@@ -381,39 +1570,57 @@ impl EfiPartionInfo {
         None
     }
 
-    fn get_efi_binaries(&self) -> Vec<PathBuf> {
-        let mut efi_binaries = Vec::new();
-        if let Some(partition_device) = self.get_partiton_device() {
-            let mut had_to_be_mounted = false;
-            let mount_dir = match get_mount_dir(&partition_device) {
-                Some(path) => path,
-                None => {
-                    had_to_be_mounted = true;
-                    let temp_mount_dir = create_temp_mount_dir().unwrap();
-
-                    let _ = Command::new("mount")
-                        .args([partition_device.as_os_str(), temp_mount_dir.as_os_str()])
-                        .output();
-                    temp_mount_dir
+    /// run `f` with the mount point of the partition, mounting it temporarily if needed
+    fn with_mount_dir<T>(&self, f: impl FnOnce(&Path) -> T) -> Option<T> {
+        let partition_device = self.get_partiton_device()?;
+        let mut had_to_be_mounted = false;
+        let mount_dir = match get_mount_dir(&partition_device) {
+            Some(path) => path,
+            None => {
+                had_to_be_mounted = true;
+                let temp_mount_dir = create_temp_mount_dir().unwrap();
+
+                let mounted = Command::new("mount")
+                    .args([partition_device.as_os_str(), temp_mount_dir.as_os_str()])
+                    .output()
+                    .is_ok_and(|output| output.status.success());
+                if !mounted {
+                    // an empty directory would look like a partition without any files
+                    let _ = fs::remove_dir(&temp_mount_dir);
+                    return None;
                 }
-            };
-            efi_binaries.append(
-                &mut get_efi_binaries(&mount_dir)
-                    .iter_mut()
-                    .map(|efi_bin_path| {
-                        efi_bin_path.strip_prefix(&mount_dir).unwrap().to_path_buf()
-                    })
-                    .collect(),
-            );
+                temp_mount_dir
+            }
+        };
+        let result = f(&mount_dir);
 
-            if had_to_be_mounted {
-                let _ = Command::new("umount")
-                    .args([mount_dir.as_os_str()])
-                    .output();
-                fs::remove_dir_all(&mount_dir).unwrap();
+        if had_to_be_mounted {
+            let unmounted = Command::new("umount")
+                .args([mount_dir.as_os_str()])
+                .output()
+                .is_ok_and(|output| output.status.success());
+            // removing the mount point of a still mounted partition would delete its files
+            if !unmounted {
+                eprintln!(
+                    "Could not unmount {} from {}, leaving it mounted.",
+                    partition_device.display(),
+                    mount_dir.display()
+                );
+            } else if let Err(err) = fs::remove_dir(&mount_dir) {
+                eprintln!("Could not remove {}: {err}", mount_dir.display());
             }
         }
-        efi_binaries
+        Some(result)
+    }
+
+    fn get_efi_binaries(&self) -> Vec<PathBuf> {
+        self.with_mount_dir(|mount_dir| {
+            get_efi_binaries(mount_dir)
+                .iter()
+                .map(|efi_bin_path| efi_bin_path.strip_prefix(mount_dir).unwrap().to_path_buf())
+                .collect()
+        })
+        .unwrap_or_default()
     }
 
     fn existing_boot_entries(&self) -> BTreeMap<PathBuf, BootEntry> {
@@ -423,17 +1630,8 @@ impl EfiPartionInfo {
                 if let Ok(entry) = entry.0 {
                     if let Some(boot_path) = entry.entry.clone().file_path_list {
                         for efi_bin in self.get_efi_binaries() {
-                            let mut boot_file_path = boot_path
-                                .file_path
-                                .path
-                                .to_string_lossy()
-                                .to_string()
-                                .replace("\\", "/");
-                            if boot_file_path.starts_with("/") {
-                                boot_file_path = boot_file_path.replacen("/", "", 1);
-                            }
                             if boot_path.hard_drive.partition_sig == self.info.part_guid
-                                && boot_file_path == efi_bin.to_string_lossy().to_string()
+                                && boot_entry_file(&boot_path) == efi_bin
                             {
                                 boot_entries_map.insert(efi_bin, entry.entry.clone());
                             }
@@ -445,6 +1643,34 @@ impl EfiPartionInfo {
         boot_entries_map
     }
 
+    /// all firmware boot entries pointing into this partition by id together with their image
+    fn boot_entries_by_id(&self) -> Result<BTreeMap<u16, (PathBuf, BootEntry)>, efivar::Error> {
+        let mut boot_entries_map = BTreeMap::new();
+        for entry in efivar::system().get_boot_entries()? {
+            if let Ok(entry) = entry.0 {
+                if let Some(boot_path) = &entry.entry.file_path_list {
+                    if boot_path.hard_drive.partition_sig == self.info.part_guid {
+                        boot_entries_map
+                            .insert(entry.id, (boot_entry_file(boot_path), entry.entry));
+                    }
+                }
+            }
+        }
+        Ok(boot_entries_map)
+    }
+
+    /// hard drive device path of this partition as stored in boot entries
+    fn hard_drive(&self) -> EFIHardDrive {
+        EFIHardDrive {
+            partition_number: self.part_nr,
+            partition_start: self.info.first_lba,
+            partition_size: (self.info.last_lba + 1) - self.info.first_lba,
+            partition_sig: self.info.part_guid,
+            format: 2,
+            sig_type: efivar::boot::EFIHardDriveType::Gpt,
+        }
+    }
+
     fn gen_boot_entry(&self, efi_bin: &Path, name: String) -> BootEntry {
         BootEntry {
             attributes: BootEntryAttributes::LOAD_OPTION_ACTIVE,
@@ -454,20 +1680,38 @@ impl EfiPartionInfo {
                     path: Path::new(&efi_bin.to_string_lossy().to_string().replace("/", "\\"))
                         .to_path_buf(),
                 },
-                hard_drive: EFIHardDrive {
-                    partition_number: self.part_nr,
-                    partition_start: self.info.first_lba,
-                    partition_size: (self.info.last_lba + 1) - self.info.first_lba,
-                    partition_sig: self.info.part_guid,
-                    format: 2,
-                    sig_type: efivar::boot::EFIHardDriveType::Gpt,
-                },
+                hard_drive: self.hard_drive(),
             }),
             optional_data: Vec::new(),
         }
     }
 }
 
+/// path of the image a boot entry points to, relative to the root of its partition
+fn boot_entry_file(boot_path: &FilePathList) -> PathBuf {
+    let boot_file_path = boot_path
+        .file_path
+        .path
+        .to_string_lossy()
+        .to_string()
+        .replace("\\", "/");
+    PathBuf::from(boot_file_path.trim_start_matches('/'))
+}
+
+/// the mounted efi partition containing `dir` together with its mount point
+fn find_efi_partition(dir: &Path) -> Option<(EfiPartionInfo, PathBuf)> {
+    get_efi_partitions()
+        .into_iter()
+        .filter_map(|efi_part| {
+            let mount_dir = efi_part
+                .get_partiton_device()
+                .and_then(|device| get_mount_dir(&device))?;
+            Some((efi_part, mount_dir))
+        })
+        .filter(|(_, mount_dir)| dir.starts_with(mount_dir))
+        .max_by_key(|(_, mount_dir)| mount_dir.components().count())
+}
+
 fn create_temp_mount_dir() -> io::Result<PathBuf> {
     let unique_id = std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
@@ -519,34 +1763,73 @@ fn get_efi_binaries(path: &Path) -> Vec<PathBuf> {
     binaries
 }
 
-fn get_efi_partitions() -> Vec<EfiPartionInfo> {
-    let mut efi_partitions = Vec::new();
+/// all partitions of all gpt disks
+fn get_gpt_partitions() -> Vec<EfiPartionInfo> {
+    let mut partitions = Vec::new();
     for disk in get_disk_device_paths() {
         if let Ok(gpt_info) = gpt::disk::read_disk(&disk) {
             for (nr, part) in gpt_info.partitions().into_iter() {
-                if part.part_type_guid == partition_types::EFI {
-                    efi_partitions.push(EfiPartionInfo {
-                        part_nr: *nr,
-                        disk_device: disk.clone(),
-                        info: part.clone(),
-                    });
-                }
+                partitions.push(EfiPartionInfo {
+                    part_nr: *nr,
+                    disk_device: disk.clone(),
+                    info: part.clone(),
+                });
             }
         }
     }
-    efi_partitions
+    partitions
 }
 
-fn add_boot_entry(entry: BootEntry, boot_position: Option<usize>) {
-    if let Ok(mut boot_order) = efivar::system().get_boot_order() {
-        let boot_id = get_free_boot_id(&boot_order);
-        let _ = efivar::system().add_boot_entry(boot_id, entry);
-        match boot_position {
-            Some(boot_position) => boot_order.insert(boot_position, boot_id),
-            None => boot_order.push(boot_id),
-        }
-        let _ = efivar::system().set_boot_order(boot_order);
+fn get_efi_partitions() -> Vec<EfiPartionInfo> {
+    get_gpt_partitions()
+        .into_iter()
+        .filter(|part| part.info.part_type_guid == partition_types::EFI)
+        .collect()
+}
+
+/// ids of all `Boot####` variables, including entries that cannot be decoded
+fn boot_variable_ids() -> Result<Vec<u16>, efivar::Error> {
+    Ok(efivar::system()
+        .get_boot_entries()?
+        .filter_map(|(_, variable)| parse_boot_id(variable.name()).ok())
+        .collect())
+}
+
+/// ids of all `Boot####` variables and of the boot order
+fn used_boot_ids() -> Result<Vec<u16>, efivar::Error> {
+    let mut used_ids = efivar::system().get_boot_order()?;
+    used_ids.extend(boot_variable_ids()?);
+    Ok(used_ids)
+}
+
+fn add_boot_entry(entry: BootEntry, boot_position: Option<usize>) -> Result<(), efivar::Error> {
+    let mut boot_order = efivar::system().get_boot_order()?;
+    let boot_id = get_free_boot_id(&used_boot_ids()?);
+    efivar::system().add_boot_entry(boot_id, entry)?;
+    match boot_position {
+        Some(boot_position) => boot_order.insert(boot_position, boot_id),
+        None => boot_order.push(boot_id),
+    }
+    efivar::system().set_boot_order(boot_order)
+}
+
+/// read the raw data of the firmware boot entry `id`, also for entries efivar cannot decode
+fn read_boot_variable(id: u16) -> Result<(Vec<u8>, VarFlags), ManagerError> {
+    if !boot_variable_ids()?.contains(&id) {
+        return Err(ManagerError::BootEntryNotFound(format!("{id:04X}")));
+    }
+    Ok(efivar::system().read(&Variable::new(&format!("Boot{id:04X}")))?)
+}
+
+/// delete the firmware boot entry `id` and remove it from the boot order
+fn delete_boot_entry(id: u16) -> Result<(), efivar::Error> {
+    let mut boot_order = efivar::system().get_boot_order()?;
+    efivar::system().delete(&Variable::new(&format!("Boot{id:04X}")))?;
+    if boot_order.contains(&id) {
+        boot_order.retain(|boot_id| *boot_id != id);
+        efivar::system().set_boot_order(boot_order)?;
     }
+    Ok(())
 }
 
 fn get_free_boot_id(boot_order: &Vec<u16>) -> u16 {
@@ -563,7 +1846,7 @@ fn get_free_boot_id(boot_order: &Vec<u16>) -> u16 {
 
 #[cfg(test)]
 mod boot_nr_gen_tests {
-    use crate::get_free_boot_id;
+    use crate::{get_free_boot_id, parse_boot_id};
 
     #[test]
     fn gen_boot_number_test() {
@@ -571,6 +1854,14 @@ mod boot_nr_gen_tests {
         assert_eq!(get_free_boot_id(&vec![0, 2]), 1);
         assert_eq!(get_free_boot_id(&vec![0, 10, 11, 1]), 2);
     }
+
+    #[test]
+    fn parse_boot_id_test() {
+        assert_eq!(parse_boot_id("0003"), Ok(3));
+        assert_eq!(parse_boot_id("Boot001A"), Ok(0x1a));
+        assert!(parse_boot_id("Boot").is_err());
+        assert!(parse_boot_id("zero").is_err());
+    }
 }
 
 struct BootOrderData {
@@ -584,89 +1875,163 @@ impl Display for BootOrderData {
     }
 }
 
-fn main() {
-    let args = DracutCmdArgs::parse();
+fn boot_order_handler(dry_run: bool) -> Result<(), ManagerError> {
+    let boot_order = efivar::system().get_boot_order()?;
+    let mut boot_id_map = BTreeMap::new();
+    for entry in efivar::system().get_boot_entries()? {
+        if let Ok(boot_entry) = entry.0 {
+            boot_id_map.insert(boot_entry.id, boot_entry.entry.description);
+        }
+    }
+    let boot_order_names: Vec<BootOrderData> = boot_order
+        .into_iter()
+        .map(|id| {
+            let name = boot_id_map
+                .get(&id)
+                .cloned()
+                .unwrap_or_else(|| format!("Boot{id:04X}"));
+            BootOrderData { id, name }
+        })
+        .collect();
+    let new_order = dialoguer::Sort::new()
+        .with_prompt(
+            "What boot order do you prefer? … Use space to select and arrow keys to navigate",
+        )
+        .items(&boot_order_names)
+        .interact()?;
+    let new_boot_order: Vec<u16> = new_order
+        .into_iter()
+        .map(|pos| boot_order_names.get(pos).unwrap())
+        .map(|b| b.id)
+        .collect();
 
-    let settings: Option<EfiStubBuildConfig> = Config::builder()
+    if dry_run {
+        println!(
+            "Would set boot order to {}",
+            new_boot_order
+                .iter()
+                .map(|id| format!("{id:04X}"))
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+        return Ok(());
+    }
+    efivar::system().set_boot_order(new_boot_order)?;
+    Ok(())
+}
+
+fn load_settings() -> Result<EfiStubBuildConfig, ManagerError> {
+    let settings: EfiStubBuildConfig = Config::builder()
         .add_source(config::File::with_name(SETTINGS_FILE))
-        .build_cloned()
-        .and_then(|settings_file| settings_file.try_deserialize())
-        .ok();
+        .build_cloned()?
+        .try_deserialize()?;
+    settings.check_namespace()?;
+    Ok(settings)
+}
 
-    match args.command {
+fn run(command: DracutBuilderCommands, dry_run: bool) -> Result<(), ManagerError> {
+    match command {
         DracutBuilderCommands::List => {
-            if let Some(settings) = settings {
-                let all_found_kernels = list_all_recognized_kernel_module_directories(&settings);
-                for (kind, kernels) in all_found_kernels.iter() {
-                    println!("{kind}:");
-                    for k in kernels {
-                        println!("- {} {}", k.full_name, k.version)
-                    }
+            let settings = load_settings()?;
+            let all_found_kernels = list_all_recognized_kernel_module_directories(&settings);
+            for (kind, kernels) in all_found_kernels.iter() {
+                println!("{kind}:");
+                for k in kernels {
+                    println!("- {} {}", k.full_name, k)
                 }
-            } else {
-                eprintln!("Build configuration not found!");
             }
+            Ok(())
         }
-        DracutBuilderCommands::Build => {
-            if let Some(settings) = settings {
-                build_efi_binaries(&settings)
-            } else {
-                eprintln!("Build configuration not found!");
-            }
+        DracutBuilderCommands::Build {
+            kernel,
+            kver,
+            verbose,
+        } => build_efi_binaries(
+            &load_settings()?,
+            kernel.as_deref(),
+            kver.as_deref(),
+            verbose,
+            dry_run,
+        ),
+        DracutBuilderCommands::Clean => clean_efi_binaries(&load_settings()?, dry_run),
+        DracutBuilderCommands::Bootentries {
+            action: Some(BootentriesCommands::Prune { yes }),
+            ..
+        } => boot_entries::prune_boot_entries(yes, dry_run),
+        DracutBuilderCommands::Bootentries {
+            action: Some(BootentriesCommands::Repair { yes }),
+            ..
+        } => boot_entries::repair_boot_entries(yes, dry_run),
+        DracutBuilderCommands::Bootentries {
+            action:
+                Some(BootentriesCommands::Edit {
+                    id,
+                    description,
+                    active,
+                    hidden,
+                    category,
+                }),
+            ..
+        } => boot_entries::edit_boot_entry(
+            id,
+            boot_entries::EntryEdit {
+                description,
+                active,
+                hidden,
+                category,
+            },
+            dry_run,
+        ),
+        DracutBuilderCommands::Bootentries {
+            action: Some(BootentriesCommands::Delete { id, yes }),
+            ..
+        } => boot_entries::delete_boot_entry_by_id(id, yes, dry_run),
+        DracutBuilderCommands::Bootentries {
+            apply: true, yes, ..
+        } => boot_entries::apply_boot_entries(&load_settings()?, yes, dry_run),
+        DracutBuilderCommands::Bootentries { apply: false, .. } => {
+            boot_entries_handler(load_settings().ok().as_ref(), dry_run)
         }
-        DracutBuilderCommands::Clean => {
-            if let Some(settings) = settings {
-                clean_efi_binaries(&settings)
-            } else {
-                eprintln!("Build configuration not found!");
-            }
+        DracutBuilderCommands::Bootorder {
+            first: Some(selector),
+            ..
+        } => boot_order::boot_first(&selector, dry_run),
+        DracutBuilderCommands::Bootorder { set: Some(ids), .. } => {
+            boot_order::replace_boot_order(ids, dry_run)
         }
-        DracutBuilderCommands::Bootentries => {
-            boot_entries_handler();
-        }
-        DracutBuilderCommands::Bootorder => {
-            if let Ok(boot_order) = efivar::system().get_boot_order() {
-                if let Ok(boot_id_map) =
-                    efivar::system()
-                        .get_boot_entries()
-                        .and_then(|boot_entries| {
-                            Ok(boot_entries
-                                .into_iter()
-                                .map(|entry| {
-                                    if let Ok(boot_entry) = entry.0 {
-                                        Some((boot_entry.id, boot_entry.entry.description))
-                                    } else {
-                                        None
-                                    }
-                                })
-                                .flatten()
-                                .collect::<BTreeMap<u16, String>>())
-                        })
-                {
-                    let boot_order_names: Vec<BootOrderData> = boot_order
-                        .into_iter()
-                        .map(|id| {
-                            let name = boot_id_map.get(&id).unwrap();
-                            BootOrderData {
-                                id,
-                                name: name.to_string(),
-                            }
-                        })
-                        .collect();
-                    let new_order = dialoguer::Sort::new()
-                        .with_prompt("What boot order do you prefer? … Use space to select and arrow keys to navigate")
-                        .items(&boot_order_names)
-                        .interact()
-                        .unwrap();
-                    let new_boot_order: Vec<u16> = new_order
-                        .into_iter()
-                        .map(|pos| boot_order_names.get(pos).unwrap())
-                        .map(|b| b.id)
-                        .collect();
+        DracutBuilderCommands::Bootorder {
+            remove: Some(selector),
+            ..
+        } => boot_order::remove_from_boot_order(&selector, dry_run),
+        DracutBuilderCommands::Bootorder { json: true, .. } => {
+            boot_order::print_boot_entries_json()
+        }
+        DracutBuilderCommands::Bootorder { .. } => boot_order_handler(dry_run),
+        DracutBuilderCommands::MarkGood => boot_assessment::mark_good(dry_run),
+        DracutBuilderCommands::Timeout { seconds } => firmware::timeout_handler(seconds, dry_run),
+        DracutBuilderCommands::Nvram {
+            command: NvramCommands::Backup { file },
+        } => nvram::backup(&file, dry_run),
+        DracutBuilderCommands::Nvram {
+            command: NvramCommands::Restore { file, yes },
+        } => nvram::restore(&file, yes, dry_run),
+        DracutBuilderCommands::FirmwareSetup { reboot, cancel } => {
+            firmware::firmware_setup_handler(reboot, cancel, dry_run)
+        }
+        DracutBuilderCommands::RebootInto { entry, reboot } => {
+            boot_order::reboot_into(&entry, reboot, dry_run)
+        }
+    }
+}
 
-                    let _ = efivar::system().set_boot_order(new_boot_order);
-                }
-            }
+fn main() -> ExitCode {
+    let args = DracutCmdArgs::parse();
+
+    match run(args.command, args.dry_run) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            err.exit_code()
         }
     }
 }
@@ -0,0 +1,230 @@
+//! Removal of stale kernel modules directories
+//!
+//! Package managers leave the modules directory of an uninstalled kernel behind when it still
+//! contains files they did not install, like `modules.dep` generated by depmod or modules built
+//! by DKMS. Such directories are removed unless the kernel is still in use, still owned by a
+//! package or DKMS still tracks modules for it, which happens while a kernel is being installed
+//! and its image is not in place yet.
+use std::{
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::kernel_discovery::KernelDiscovery;
+
+/// why a modules directory is not removed
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeepReason {
+    Running,
+    Installed,
+    PackageOwned(String),
+    PendingDkms,
+}
+
+impl Display for KeepReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeepReason::Running => f.write_str("kernel is running"),
+            KeepReason::Installed => f.write_str("kernel is installed"),
+            KeepReason::PackageOwned(package) => write!(f, "owned by package {package}"),
+            KeepReason::PendingDkms => f.write_str("contains DKMS modules of a pending kernel"),
+        }
+    }
+}
+
+/// ownership queries of the supported package managers, the first one available is used
+const OWNER_QUERIES: [(&str, &[&str]); 3] = [
+    ("pacman", &["-Qqo"]),
+    ("dpkg-query", &["-S"]),
+    ("rpm", &["-qf", "--queryformat", "%{NAME}\n"]),
+];
+
+/// name of the installed package owning `path`
+fn owning_package(path: &Path) -> Option<String> {
+    for (program, args) in OWNER_QUERIES {
+        let Ok(output) = Command::new(program).args(args).arg(path).output() else {
+            // package manager not available on this system
+            continue;
+        };
+        if !output.status.success() {
+            return None;
+        }
+        let package = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .next()
+            // dpkg-query prints `package: path`
+            .map(|line| line.split(':').next().unwrap_or(line).trim().to_string())
+            .filter(|package| !package.is_empty());
+        return package;
+    }
+    None
+}
+
+/// check if `updates/` contains modules DKMS still tracks for kernel `release`
+///
+/// Without dkms being available any modules in `updates/` are considered pending.
+fn has_pending_dkms_modules(path: &Path, release: &str) -> bool {
+    if !contains_modules(&path.join("updates")) {
+        return false;
+    }
+    match Command::new("dkms")
+        .args(["status", "-k", release])
+        .output()
+    {
+        Ok(output) => output.status.success() && !output.stdout.trim_ascii().is_empty(),
+        Err(_) => true,
+    }
+}
+
+fn contains_modules(dir: &Path) -> bool {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        let file_type = entry.file_type();
+        if file_type.as_ref().is_ok_and(|t| t.is_dir()) {
+            contains_modules(&entry.path())
+        } else {
+            entry.file_name().to_string_lossy().contains(".ko")
+        }
+    })
+}
+
+/// the reason the modules directory `path` of kernel `release` has to be kept, if any
+pub fn keep_reason(
+    discovery: &KernelDiscovery,
+    path: &Path,
+    release: &str,
+    running_kernel: &str,
+) -> Option<KeepReason> {
+    if release == running_kernel {
+        Some(KeepReason::Running)
+    } else if discovery.kernel_image(release).is_some() {
+        Some(KeepReason::Installed)
+    } else if let Some(package) = owning_package(path) {
+        Some(KeepReason::PackageOwned(package))
+    } else if has_pending_dkms_modules(path, release) {
+        Some(KeepReason::PendingDkms)
+    } else {
+        None
+    }
+}
+
+/// total size of all files below `path` in bytes, symlinks like `build` are not followed
+pub fn directory_size(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| directory_size(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// modules directories of all kernels inside `modules_dir` by release
+pub fn module_directories(modules_dir: &Path) -> Vec<(String, PathBuf)> {
+    let mut directories: Vec<(String, PathBuf)> = fs::read_dir(modules_dir)
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter(|entry| entry.file_type().is_ok_and(|t| t.is_dir()))
+                .filter_map(|entry| Some((entry.file_name().into_string().ok()?, entry.path())))
+                .collect()
+        })
+        .unwrap_or_default();
+    directories.sort();
+    directories
+}
+
+#[cfg(test)]
+mod module_cleanup_tests {
+    use std::{fs, os::unix::fs::symlink, process::Command};
+
+    use super::{contains_modules, directory_size, keep_reason, module_directories, KeepReason};
+    use crate::kernel_discovery::{KernelDiscovery, DEFAULT_LAYOUTS};
+
+    #[test]
+    fn keep_reason_test() {
+        let root = std::env::temp_dir().join(format!("module_cleanup_{}", std::process::id()));
+        let modules_dir = root.join("modules");
+        let boot_dir = root.join("boot");
+        for dir in [
+            "6.9.1-arch1-1/kernel",
+            "6.8.9-arch1-1/updates/dkms",
+            "6.7.0-arch1-1",
+        ] {
+            fs::create_dir_all(modules_dir.join(dir)).unwrap();
+        }
+        fs::create_dir_all(&boot_dir).unwrap();
+        fs::write(boot_dir.join("initramfs-linux.img"), [0; 1000]).unwrap();
+        fs::write(modules_dir.join("6.9.1-arch1-1/vmlinuz"), [0; 100]).unwrap();
+        fs::write(
+            modules_dir.join("6.9.1-arch1-1/kernel/ext4.ko.zst"),
+            [0; 20],
+        )
+        .unwrap();
+        fs::write(
+            modules_dir.join("6.8.9-arch1-1/updates/dkms/zfs.ko"),
+            [0; 10],
+        )
+        .unwrap();
+        fs::write(modules_dir.join("6.7.0-arch1-1/modules.dep"), [0; 5]).unwrap();
+        // symlinks like `build` point into other trees and are not counted
+        symlink(&boot_dir, modules_dir.join("6.9.1-arch1-1/build")).unwrap();
+
+        let discovery = KernelDiscovery {
+            modules_dir: &modules_dir,
+            boot_dir: &boot_dir,
+            layouts: &DEFAULT_LAYOUTS,
+        };
+        let release_dir = |release| modules_dir.join(release);
+        let keep =
+            |release, running| keep_reason(&discovery, &release_dir(release), release, running);
+        let dkms_available = Command::new("dkms").arg("--version").output().is_ok();
+
+        assert_eq!(
+            module_directories(&modules_dir)
+                .into_iter()
+                .map(|(release, _)| release)
+                .collect::<Vec<_>>(),
+            vec!["6.7.0-arch1-1", "6.8.9-arch1-1", "6.9.1-arch1-1"]
+        );
+        assert!(contains_modules(&release_dir("6.8.9-arch1-1")));
+        assert!(!contains_modules(&release_dir("6.7.0-arch1-1")));
+        let link_size = fs::symlink_metadata(release_dir("6.9.1-arch1-1").join("build"))
+            .unwrap()
+            .len();
+        assert_eq!(
+            directory_size(&release_dir("6.9.1-arch1-1")),
+            120 + link_size
+        );
+
+        // the running kernel is reported before its image
+        assert_eq!(
+            keep("6.9.1-arch1-1", "6.9.1-arch1-1"),
+            Some(KeepReason::Running)
+        );
+        assert_eq!(
+            keep("6.9.1-arch1-1", "6.8.9-arch1-1"),
+            Some(KeepReason::Installed)
+        );
+        if !dkms_available {
+            assert_eq!(
+                keep("6.8.9-arch1-1", "6.9.1-arch1-1"),
+                Some(KeepReason::PendingDkms)
+            );
+        }
+        assert_eq!(keep("6.7.0-arch1-1", "6.9.1-arch1-1"), None);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
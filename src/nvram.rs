@@ -0,0 +1,318 @@
+//! Backup and restore of the firmware boot entries
+//!
+//! Every `Boot####` variable is stored as raw variable data, together with `BootOrder`, and is
+//! restored from it byte for byte. For readability the description, attributes, optional data and
+//! the hard drive device path are stored decoded as well, entries with only decoded data are
+//! restored from it. Backups ending in `.json` are written as json, all others as toml.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use efivar::{
+    boot::{
+        BootEntry, BootEntryAttributes, EFIHardDrive, EFIHardDriveType, FilePath, FilePathList,
+    },
+    efi::Variable,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    boot_entries::{load_option_attributes, load_option_description},
+    boot_order::format_boot_order,
+    error::ManagerError,
+    firmware::variable_flags,
+    parse_boot_id,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NvramBackup {
+    /// ids in boot order, e.g. `0003`
+    pub boot_order: Vec<String>,
+    pub entries: Vec<BackupEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupEntry {
+    /// id of the `Boot####` variable, e.g. `0003`
+    pub id: String,
+    pub description: String,
+    pub attributes: u32,
+    /// optional data passed to the image as hex
+    #[serde(default)]
+    pub optional_data: String,
+    /// complete variable data as hex, restored as is when present
+    pub raw: Option<String>,
+    pub device_path: Option<BackupDevicePath>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupDevicePath {
+    pub partition_number: u32,
+    pub partition_start: u64,
+    pub partition_size: u64,
+    pub partition_guid: String,
+    /// image path on the partition, e.g. `\EFI\Linux\arch.efi`
+    pub file: String,
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, ManagerError> {
+    let invalid = || ManagerError::NvramBackup(format!("`{hex}` is not valid hex data"));
+    if !hex.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|pos| {
+            hex.get(pos..pos + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
+
+fn boot_variable(id: u16) -> Variable {
+    Variable::new(&format!("Boot{id:04X}"))
+}
+
+impl BackupEntry {
+    /// decode a boot entry and keep its raw variable data
+    fn new(id: u16, entry: &BootEntry, raw: impl FnOnce() -> Option<Vec<u8>>) -> BackupEntry {
+        let device_path = entry
+            .file_path_list
+            .as_ref()
+            .filter(|boot_path| boot_path.hard_drive.sig_type == EFIHardDriveType::Gpt)
+            .map(|boot_path| BackupDevicePath {
+                partition_number: boot_path.hard_drive.partition_number,
+                partition_start: boot_path.hard_drive.partition_start,
+                partition_size: boot_path.hard_drive.partition_size,
+                partition_guid: boot_path.hard_drive.partition_sig.to_string(),
+                file: boot_path.file_path.path.to_string_lossy().to_string(),
+            });
+        BackupEntry {
+            id: format!("{id:04X}"),
+            description: entry.description.clone(),
+            attributes: entry.attributes.bits(),
+            optional_data: to_hex(&entry.optional_data),
+            raw: raw().map(|data| to_hex(&data)),
+            device_path,
+        }
+    }
+
+    /// an entry efivar could not decode, only its raw variable data is kept
+    fn undecoded(id: u16, data: &[u8]) -> BackupEntry {
+        BackupEntry {
+            id: format!("{id:04X}"),
+            description: load_option_description(data),
+            attributes: load_option_attributes(data).bits(),
+            optional_data: String::new(),
+            raw: Some(to_hex(data)),
+            device_path: None,
+        }
+    }
+
+    /// the boot entry described by the backup, `None` for entries only stored as raw data
+    fn boot_entry(&self) -> Result<Option<BootEntry>, ManagerError> {
+        let Some(device_path) = &self.device_path else {
+            return Ok(None);
+        };
+        Ok(Some(BootEntry {
+            attributes: BootEntryAttributes::from_bits_retain(self.attributes),
+            description: self.description.clone(),
+            file_path_list: Some(FilePathList {
+                file_path: FilePath {
+                    path: PathBuf::from(&device_path.file),
+                },
+                hard_drive: EFIHardDrive {
+                    partition_number: device_path.partition_number,
+                    partition_start: device_path.partition_start,
+                    partition_size: device_path.partition_size,
+                    partition_sig: device_path.partition_guid.parse().map_err(|_| {
+                        ManagerError::NvramBackup(format!(
+                            "`{}` is not a partition GUID",
+                            device_path.partition_guid
+                        ))
+                    })?,
+                    format: 2,
+                    sig_type: EFIHardDriveType::Gpt,
+                },
+            }),
+            optional_data: from_hex(&self.optional_data)?,
+        }))
+    }
+}
+
+fn is_json(file: &Path) -> bool {
+    file.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+}
+
+fn serialize_backup(file: &Path, backup: &NvramBackup) -> Result<String, ManagerError> {
+    if is_json(file) {
+        serde_json::to_string_pretty(backup)
+            .map_err(|err| ManagerError::NvramBackup(err.to_string()))
+    } else {
+        toml::to_string_pretty(backup).map_err(|err| ManagerError::NvramBackup(err.to_string()))
+    }
+}
+
+fn deserialize_backup(file: &Path, content: &str) -> Result<NvramBackup, ManagerError> {
+    if is_json(file) {
+        serde_json::from_str(content).map_err(|err| ManagerError::NvramBackup(err.to_string()))
+    } else {
+        toml::from_str(content).map_err(|err| ManagerError::NvramBackup(err.to_string()))
+    }
+}
+
+/// write all boot entries and the boot order to `file`
+pub fn backup(file: &Path, dry_run: bool) -> Result<(), ManagerError> {
+    let boot_order = efivar::system().get_boot_order()?;
+    let mut entries = Vec::new();
+    for (boot_var, variable) in efivar::system().get_boot_entries()? {
+        let read_raw = || efivar::system().read(&variable).ok().map(|(data, _)| data);
+        match boot_var {
+            Ok(boot_var) => entries.push(BackupEntry::new(boot_var.id, &boot_var.entry, read_raw)),
+            Err(_) => match (parse_boot_id(variable.name()), read_raw()) {
+                (Ok(id), Some(data)) => entries.push(BackupEntry::undecoded(id, &data)),
+                _ => eprintln!("Could not read boot variable {}", variable.name()),
+            },
+        }
+    }
+    entries.sort_by(|a, b| a.id.cmp(&b.id));
+    let raw_entries = entries
+        .iter()
+        .filter(|entry| entry.device_path.is_none())
+        .count();
+    if raw_entries > 0 {
+        println!("{raw_entries} boot entries without a gpt device path are only stored as raw variable data");
+    }
+    let backup = NvramBackup {
+        boot_order: boot_order.iter().map(|id| format!("{id:04X}")).collect(),
+        entries,
+    };
+    let content = serialize_backup(file, &backup)?;
+    if dry_run {
+        println!(
+            "Would write {} boot entries and boot order {} to {}",
+            backup.entries.len(),
+            format_boot_order(&boot_order),
+            file.display()
+        );
+        return Ok(());
+    }
+    fs::write(file, content).map_err(|err| {
+        ManagerError::NvramBackup(format!("could not write {}: {err}", file.display()))
+    })?;
+    println!(
+        "Saved {} boot entries and boot order {} to {}",
+        backup.entries.len(),
+        format_boot_order(&boot_order),
+        file.display()
+    );
+    Ok(())
+}
+
+/// write all boot entries and the boot order of the backup in `file` back to the firmware
+///
+/// Entries with the same id are overwritten, other existing entries are left alone but are not
+/// part of the restored boot order.
+pub fn restore(file: &Path, yes: bool, dry_run: bool) -> Result<(), ManagerError> {
+    let content = fs::read_to_string(file).map_err(|err| {
+        ManagerError::NvramBackup(format!("could not read {}: {err}", file.display()))
+    })?;
+    let backup = deserialize_backup(file, &content)?;
+    let parse_id = |id: &str| parse_boot_id(id).map_err(ManagerError::NvramBackup);
+    let boot_order = backup
+        .boot_order
+        .iter()
+        .map(|id| parse_id(id))
+        .collect::<Result<Vec<u16>, _>>()?;
+    let mut writes = Vec::new();
+    for entry in &backup.entries {
+        let id = parse_id(&entry.id)?;
+        let data = match (&entry.raw, entry.boot_entry()?) {
+            (Some(raw), _) => from_hex(raw)?,
+            (None, Some(boot_entry)) => boot_entry.to_bytes(),
+            (None, None) => {
+                return Err(ManagerError::NvramBackup(format!(
+                    "entry {} has neither a device path nor raw data",
+                    entry.id
+                )))
+            }
+        };
+        println!("Restore boot entry {id:04X} `{}`", entry.description);
+        writes.push((id, data));
+    }
+    println!("Restore boot order {}", format_boot_order(&boot_order));
+    if dry_run {
+        println!("Dry run, no boot entries were restored.");
+        return Ok(());
+    }
+    if !yes
+        && !dialoguer::Confirm::new()
+            .with_prompt("Write these boot entries to the firmware?")
+            .interact()?
+    {
+        return Ok(());
+    }
+    for (id, data) in writes {
+        efivar::system().write(&boot_variable(id), variable_flags(), &data)?;
+    }
+    efivar::system().set_boot_order(boot_order)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod nvram_backup_tests {
+    use std::path::Path;
+
+    use super::{
+        deserialize_backup, from_hex, serialize_backup, to_hex, BackupDevicePath, BackupEntry,
+        NvramBackup,
+    };
+
+    #[test]
+    fn backup_roundtrip_test() {
+        assert_eq!(to_hex(&[0x00, 0xab, 0x10]), "00ab10");
+        assert_eq!(from_hex("00ab10").unwrap(), vec![0x00, 0xab, 0x10]);
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+
+        let backup = NvramBackup {
+            boot_order: vec!["0001".to_string(), "0000".to_string()],
+            entries: vec![
+                BackupEntry {
+                    id: "0000".to_string(),
+                    description: "PXE".to_string(),
+                    attributes: 1,
+                    optional_data: String::new(),
+                    raw: Some("0100000004ff".to_string()),
+                    device_path: None,
+                },
+                BackupEntry {
+                    id: "0001".to_string(),
+                    description: "Arch Linux".to_string(),
+                    attributes: 1,
+                    optional_data: "00ff".to_string(),
+                    raw: None,
+                    device_path: Some(BackupDevicePath {
+                        partition_number: 1,
+                        partition_start: 2048,
+                        partition_size: 1048576,
+                        partition_guid: "0fc63daf-8483-4772-8e79-3d69d8477de4".to_string(),
+                        file: "\\ArchLinux.efi".to_string(),
+                    }),
+                },
+            ],
+        };
+        for file in ["backup.toml", "backup.json"] {
+            let file = Path::new(file);
+            let content = serialize_backup(file, &backup).unwrap();
+            assert_eq!(deserialize_backup(file, &content).unwrap(), backup);
+        }
+    }
+}